@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use memory::{MAX_ROM_SIZE, ROM_START};
+
+/// How deeply `INCLUDE`s may nest before the assembler assumes a cycle.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// How deeply constants may be defined in terms of other constants before
+/// the assembler assumes a cycle.
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+/// An error in assembly source, with where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for AsmError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Assembles the source file at `path` into a ROM image to be loaded at
+/// 0x200. Included files are found relative to the file including them.
+///
+/// The source uses the same mnemonics the disassembler produces, one
+/// instruction per line, with `;` starting a comment. As well as
+/// instructions a line can hold:
+///
+/// * `name:` to label the address of what follows.
+/// * `name EQU value` or `name = value` to define a constant.
+/// * `DB` and `DW` followed by comma separated bytes or big-endian words.
+///   `DB` also accepts strings in double quotes.
+/// * `INCLUDE "file"` to assemble another file in place.
+///
+/// Numbers are decimal, hex with a `0x`, `$` or `#` prefix, or binary with
+/// a `0b` or `%` prefix. Anywhere a number is expected, labels, constants
+/// and sums or differences of them can be used instead.
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let source = read_source(path).map_err(|err| AsmError {
+        file: name.clone(),
+        line: 0,
+        message: format!("Cannot read '{}': {}", name, err),
+    })?;
+    let mut lines = Vec::new();
+    read_lines(&name, &source, path.parent(), 0, &mut lines)?;
+    assemble_lines(&lines)
+}
+
+/// Assembles `source` as `assemble_file` would. Included files are found
+/// relative to the current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    read_lines("<input>", source, None, 0, &mut lines)?;
+    assemble_lines(&lines)
+}
+
+fn read_source(path: &Path) -> io::Result<String> {
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+    Ok(source)
+}
+
+/// One line of source, with includes already expanded.
+struct Line {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl Line {
+    fn error<S: Into<String>>(&self, message: S) -> AsmError {
+        AsmError { file: self.file.clone(), line: self.number, message: message.into() }
+    }
+}
+
+fn read_lines(name: &str, source: &str, dir: Option<&Path>, depth: usize, lines: &mut Vec<Line>)
+              -> Result<(), AsmError> {
+    for (n, text) in source.lines().enumerate() {
+        let line = Line { file: name.to_string(), number: n + 1, text: strip_comment(text).trim().to_string() };
+        let (mnemonic, rest) = split_mnemonic(&line.text);
+        if !mnemonic.eq_ignore_ascii_case("include") {
+            lines.push(line);
+            continue;
+        }
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("Includes are nested too deeply"));
+        }
+        let file = match parse_string(rest.trim()) {
+            Some(file) => file,
+            None => return Err(line.error("INCLUDE needs a file name in double quotes")),
+        };
+        let path: PathBuf = match dir {
+            Some(dir) => dir.join(&file),
+            None => PathBuf::from(&file),
+        };
+        let source = read_source(&path)
+            .map_err(|err| line.error(format!("Cannot read '{}': {}", path.display(), err)))?;
+        read_lines(&path.display().to_string(), &source, path.parent(), depth + 1, lines)?;
+    }
+    Ok(())
+}
+
+/// Removes a `;` comment, ignoring any inside strings.
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => {},
+        }
+    }
+    text
+}
+
+fn split_mnemonic(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    }
+}
+
+fn parse_string(text: &str) -> Option<String> {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some(text[1..text.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits operands on commas, ignoring any inside strings.
+fn split_operands(text: &str) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => { quoted = !quoted; current.push(c); },
+            ',' if !quoted => operands.push(::std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    operands.push(current);
+    operands.iter().map(|op| op.trim().to_string()).collect()
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// A statement that produces bytes, waiting for its operands to be
+/// evaluated once every label is known.
+struct Statement<'a> {
+    line: &'a Line,
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+fn assemble_lines(lines: &[Line]) -> Result<Vec<u8>, AsmError> {
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = ROM_START as u32;
+
+    // First pass: find every label's address and every constant.
+    for line in lines {
+        let mut text = line.text.as_str();
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                break;
+            }
+            define(&mut symbols, line, label, Symbol::Address(address as i64))?;
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (first, rest) = split_mnemonic(text);
+        let (second, value) = split_mnemonic(rest);
+        if second.eq_ignore_ascii_case("equ") || second == "=" {
+            define(&mut symbols, line, first, Symbol::Constant(value.to_string()))?;
+            continue;
+        }
+        if let Some(value) = rest.strip_prefix('=') {
+            define(&mut symbols, line, first, Symbol::Constant(value.trim().to_string()))?;
+            continue;
+        }
+
+        let statement = Statement {
+            line,
+            mnemonic: first.to_uppercase(),
+            operands: split_operands(rest),
+        };
+        address += statement_len(&statement);
+        if address > ROM_START as u32 + MAX_ROM_SIZE as u32 {
+            return Err(line.error(format!("Program is larger than {} bytes", MAX_ROM_SIZE)));
+        }
+        statements.push(statement);
+    }
+
+    // Second pass: encode everything.
+    let mut rom = Vec::new();
+    for statement in statements.iter() {
+        let bytes = encode(statement, &symbols).map_err(|message| statement.line.error(message))?;
+        rom.extend(bytes);
+    }
+    Ok(rom)
+}
+
+enum Symbol {
+    Address(i64),
+    Constant(String),
+}
+
+fn define(symbols: &mut HashMap<String, Symbol>, line: &Line, name: &str, symbol: Symbol) -> Result<(), AsmError> {
+    if !is_identifier(name) {
+        return Err(line.error(format!("'{}' is not a valid name", name)));
+    }
+    if parse_operand(name) != Operand::Expr(name.to_string()) {
+        return Err(line.error(format!("'{}' is a reserved name", name)));
+    }
+    if symbols.contains_key(name) {
+        return Err(line.error(format!("'{}' is already defined", name)));
+    }
+    symbols.insert(name.to_string(), symbol);
+    Ok(())
+}
+
+fn statement_len(statement: &Statement) -> u32 {
+    match statement.mnemonic.as_str() {
+        "DB" => statement.operands.iter()
+            .map(|op| parse_string(op).map(|s| s.len() as u32).unwrap_or(1))
+            .sum(),
+        "DW" => statement.operands.len() as u32 * 2,
+        "LONG" => 4,
+        _ => 2,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Reg(u8),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Expr(String),
+}
+
+fn parse_operand(text: &str) -> Operand {
+    let upper = text.to_uppercase();
+    match upper.as_str() {
+        "I" => return Operand::I,
+        "[I]" => return Operand::IndirectI,
+        "DT" => return Operand::Dt,
+        "ST" => return Operand::St,
+        "K" => return Operand::K,
+        "F" => return Operand::F,
+        "HF" => return Operand::Hf,
+        "B" => return Operand::B,
+        "R" => return Operand::R,
+        _ => {},
+    }
+    if upper.len() == 2 && upper.starts_with('V') {
+        if let Ok(reg) = u8::from_str_radix(&upper[1..], 16) {
+            return Operand::Reg(reg);
+        }
+    }
+    Operand::Expr(text.to_string())
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_prefix('$').or_else(|| lower.strip_prefix('#')) {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_prefix('%') {
+        (digits, 2)
+    } else {
+        (&lower[..], 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// Evaluates a sum or difference of numbers and symbols.
+fn evaluate(expr: &str, symbols: &HashMap<String, Symbol>, depth: usize) -> Result<i64, String> {
+    if depth > MAX_CONSTANT_DEPTH {
+        return Err(format!("Constant '{}' refers to itself", expr));
+    }
+
+    let mut total: i64 = 0;
+    let mut sign: i64 = 1;
+    let mut term = String::new();
+    for c in expr.chars().chain(Some('+')) {
+        if c != '+' && c != '-' {
+            term.push(c);
+            continue;
+        }
+        let text = term.trim().to_string();
+        term.clear();
+        if text.is_empty() {
+            if total != 0 || sign != 1 || c == '+' {
+                return Err(format!("Missing value in '{}'", expr));
+            }
+            sign = -1;
+            continue;
+        }
+
+        let value = match parse_number(&text) {
+            Some(value) => value,
+            None => match symbols.get(&text) {
+                Some(&Symbol::Address(addr)) => addr,
+                Some(Symbol::Constant(value)) => evaluate(value, symbols, depth + 1)?,
+                None if is_identifier(&text) => return Err(format!("Undefined name '{}'", text)),
+                None => return Err(format!("Invalid number '{}'", text)),
+            },
+        };
+        total = match sign.checked_mul(value).and_then(|value| total.checked_add(value)) {
+            Some(total) => total,
+            None => return Err(format!("'{}' overflows", expr)),
+        };
+        sign = if c == '-' { -1 } else { 1 };
+    }
+    Ok(total)
+}
+
+fn encode(statement: &Statement, symbols: &HashMap<String, Symbol>) -> Result<Vec<u8>, String> {
+    let value = |expr: &str, min: i64, max: i64| -> Result<u16, String> {
+        let value = evaluate(expr, symbols, 0)?;
+        if value < min || value > max {
+            return Err(format!("{} is out of range, expected {} to {}", value, min, max));
+        }
+        Ok(value as u16)
+    };
+    let byte = |expr: &str| value(expr, -128, 0xFF).map(|v| v & 0xFF);
+    let addr = |expr: &str| value(expr, 0, 0xFFF);
+    let nibble = |expr: &str| value(expr, 0, 0xF);
+
+    let mnemonic = statement.mnemonic.as_str();
+    match mnemonic {
+        "DB" => {
+            let mut bytes = Vec::new();
+            for op in statement.operands.iter() {
+                match parse_string(op) {
+                    Some(text) => bytes.extend(text.bytes()),
+                    None => bytes.push(byte(op)? as u8),
+                }
+            }
+            return Ok(bytes);
+        },
+        "DW" => {
+            let mut bytes = Vec::new();
+            for op in statement.operands.iter() {
+                let word = value(op, -0x8000, 0xFFFF)?;
+                bytes.push((word >> 8) as u8);
+                bytes.push(word as u8);
+            }
+            return Ok(bytes);
+        },
+        _ => {},
+    }
+
+    use self::Operand::*;
+    let ops: Vec<Operand> = statement.operands.iter().map(|op| parse_operand(op)).collect();
+    let xy = |base: u16, x: u8, y: u8| base | ((x as u16) << 8) | ((y as u16) << 4);
+    let opcode = match (mnemonic, &ops[..]) {
+        ("CLS", &[]) => 0x00E0,
+        ("RET", &[]) => 0x00EE,
+        ("SCD", &[Expr(ref n)]) => 0x00C0 | nibble(n)?,
+        ("SCU", &[Expr(ref n)]) => 0x00D0 | nibble(n)?,
+        ("SCR", &[]) => 0x00FB,
+        ("SCL", &[]) => 0x00FC,
+        ("EXIT", &[]) => 0x00FD,
+        ("LOW", &[]) => 0x00FE,
+        ("HIGH", &[]) => 0x00FF,
+        ("SYS", &[Expr(ref a)]) => addr(a)?,
+        ("JP", &[Expr(ref a)]) => 0x1000 | addr(a)?,
+        ("JP", &[Reg(0), Expr(ref a)]) => 0xB000 | addr(a)?,
+        ("CALL", &[Expr(ref a)]) => 0x2000 | addr(a)?,
+        ("SE", &[Reg(x), Reg(y)]) => xy(0x5000, x, y),
+        ("SE", &[Reg(x), Expr(ref n)]) => xy(0x3000, x, 0) | byte(n)?,
+        ("SNE", &[Reg(x), Reg(y)]) => xy(0x9000, x, y),
+        ("SNE", &[Reg(x), Expr(ref n)]) => xy(0x4000, x, 0) | byte(n)?,
+        ("SAVE", &[Reg(x), Reg(y)]) => xy(0x5002, x, y),
+        ("LOAD", &[Reg(x), Reg(y)]) => xy(0x5003, x, y),
+        ("LD", &[Reg(x), Reg(y)]) => xy(0x8000, x, y),
+        ("LD", &[Reg(x), Expr(ref n)]) => xy(0x6000, x, 0) | byte(n)?,
+        ("LD", &[I, Expr(ref a)]) => 0xA000 | addr(a)?,
+        ("LD", &[Reg(x), Dt]) => xy(0xF007, x, 0),
+        ("LD", &[Reg(x), K]) => xy(0xF00A, x, 0),
+        ("LD", &[Dt, Reg(x)]) => xy(0xF015, x, 0),
+        ("LD", &[St, Reg(x)]) => xy(0xF018, x, 0),
+        ("LD", &[F, Reg(x)]) => xy(0xF029, x, 0),
+        ("LD", &[Hf, Reg(x)]) => xy(0xF030, x, 0),
+        ("LD", &[B, Reg(x)]) => xy(0xF033, x, 0),
+        ("LD", &[IndirectI, Reg(x)]) => xy(0xF055, x, 0),
+        ("LD", &[Reg(x), IndirectI]) => xy(0xF065, x, 0),
+        ("LD", &[R, Reg(x)]) => xy(0xF075, x, 0),
+        ("LD", &[Reg(x), R]) => xy(0xF085, x, 0),
+        ("ADD", &[Reg(x), Reg(y)]) => xy(0x8004, x, y),
+        ("ADD", &[Reg(x), Expr(ref n)]) => xy(0x7000, x, 0) | byte(n)?,
+        ("ADD", &[I, Reg(x)]) => xy(0xF01E, x, 0),
+        ("OR", &[Reg(x), Reg(y)]) => xy(0x8001, x, y),
+        ("AND", &[Reg(x), Reg(y)]) => xy(0x8002, x, y),
+        ("XOR", &[Reg(x), Reg(y)]) => xy(0x8003, x, y),
+        ("SUB", &[Reg(x), Reg(y)]) => xy(0x8005, x, y),
+        ("SHR", &[Reg(x)]) => xy(0x8006, x, x),
+        ("SHR", &[Reg(x), Reg(y)]) => xy(0x8006, x, y),
+        ("SUBN", &[Reg(x), Reg(y)]) => xy(0x8007, x, y),
+        ("SHL", &[Reg(x)]) => xy(0x800E, x, x),
+        ("SHL", &[Reg(x), Reg(y)]) => xy(0x800E, x, y),
+        ("RND", &[Reg(x), Expr(ref n)]) => xy(0xC000, x, 0) | byte(n)?,
+        ("DRW", &[Reg(x), Reg(y), Expr(ref n)]) => xy(0xD000, x, y) | nibble(n)?,
+        ("SKP", &[Reg(x)]) => xy(0xE09E, x, 0),
+        ("SKNP", &[Reg(x)]) => xy(0xE0A1, x, 0),
+        ("LONG", &[I, Expr(ref a)]) => {
+            let target = value(a, 0, 0xFFFF)?;
+            return Ok(vec![0xF0, 0x00, (target >> 8) as u8, target as u8]);
+        },
+        ("PLANE", &[Expr(ref n)]) => 0xF001 | (nibble(n)? << 8),
+        ("AUDIO", &[]) => 0xF002,
+        ("PITCH", &[Reg(x)]) => xy(0xF03A, x, 0),
+        _ if is_mnemonic(mnemonic) => {
+            return Err(format!("Invalid operands for {}: '{}'", mnemonic, statement.operands.join(", ")));
+        },
+        _ => return Err(format!("Unknown instruction '{}'", statement.mnemonic)),
+    };
+    Ok(vec![(opcode >> 8) as u8, opcode as u8])
+}
+
+fn is_mnemonic(mnemonic: &str) -> bool {
+    [
+        "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "SYS", "JP", "CALL", "SE",
+        "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND",
+        "DRW", "SKP", "SKNP", "LONG", "PLANE", "AUDIO", "PITCH",
+    ].contains(&mnemonic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disasm::Disassembly;
+
+    fn error(source: &str) -> (usize, String) {
+        let err = assemble(source).unwrap_err();
+        (err.line, err.message)
+    }
+
+    #[test]
+    fn instructions() {
+        let rom = assemble("CLS\nld v3, 0x12\nDRW V0, V1, 5\nLD [I], VA\nLONG I, $1234\nshr v2").unwrap();
+        assert_eq!(rom, vec![0x00, 0xE0, 0x63, 0x12, 0xD0, 0x15, 0xFA, 0x55, 0xF0, 0x00, 0x12, 0x34, 0x82, 0x26]);
+    }
+
+    #[test]
+    fn labels_and_constants() {
+        let source = "\
+SPEED EQU 3
+start:  LD I, sprite ; point at the sprite
+loop:   ADD V0, SPEED
+        JP loop
+sprite: DB %11110000, 0x90, \"A;\"
+        DW sprite + 1, -1";
+        let rom = assemble(source).unwrap();
+        assert_eq!(rom, vec![0xA2, 0x06, 0x70, 0x03, 0x12, 0x02, 0xF0, 0x90, b'A', b';', 0x02, 0x07, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn constant_cycles() {
+        assert_eq!(error("A EQU A\nLD V0, A").1, "Constant 'A' refers to itself");
+        assert!(error("FOO EQU BAR + 1\nBAR EQU FOO\nLD V0, BAR").1.ends_with("refers to itself"));
+
+        // Far deeper than includes may nest, but not a cycle.
+        let mut source: String = (1..40).map(|n| format!("C{} EQU C{} + 1\n", n, n - 1)).collect();
+        source.push_str("C0 EQU 1\nLD V0, C39");
+        assert_eq!(assemble(&source).unwrap(), vec![0x60, 40]);
+    }
+
+    #[test]
+    fn overflowing_expressions() {
+        assert_eq!(error("LD V0, 0x7FFFFFFFFFFFFFFF + 1").1, "'0x7FFFFFFFFFFFFFFF + 1' overflows");
+        assert_eq!(error("BIG EQU 0x4000000000000000\nHUGE EQU BIG + BIG\nLD V0, HUGE - 1").1, "'BIG + BIG' overflows");
+        assert_eq!(assemble("BIG EQU 0x7FFFFFFFFFFFFFFF\nLD V0, BIG - BIG + 1").unwrap(), vec![0x60, 1]);
+    }
+
+    #[test]
+    fn forward_references() {
+        let rom = assemble("CALL sub\nsub: RET").unwrap();
+        assert_eq!(rom, vec![0x22, 0x02, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        assert_eq!(error("CLS\n\nLD V0, 256"), (3, "256 is out of range, expected -128 to 255".to_string()));
+        assert_eq!(error("JP nowhere"), (1, "Undefined name 'nowhere'".to_string()));
+        assert_eq!(error("MOV V0, V1"), (1, "Unknown instruction 'MOV'".to_string()));
+        assert_eq!(error("DRW V0, 1, 2"), (1, "Invalid operands for DRW: 'V0, 1, 2'".to_string()));
+        assert_eq!(error("a:\na: CLS"), (2, "'a' is already defined".to_string()));
+        assert_eq!(error("DT EQU 1"), (1, "'DT' is a reserved name".to_string()));
+        assert_eq!(error("INCLUDE \"/nonexistent/file\"").0, 1);
+    }
+
+    #[test]
+    fn reassembles_disassembly() {
+        let rom = vec![0xA2, 0x08, 0x22, 0x0A, 0x12, 0x04, 0x00, 0xE0, 0x3C, 0x42, 0x60, 0x01, 0x00, 0xEE];
+        let mut listing = Vec::new();
+        Disassembly::new(&rom, false).write_listing(&mut listing).unwrap();
+        assert_eq!(assemble(&String::from_utf8(listing).unwrap()).unwrap(), rom);
+    }
+}
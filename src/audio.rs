@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use cpu::TIMER_FREQUENCY;
+
+/// The tone pitch, in Hz, used unless told otherwise.
+pub const DEFAULT_PITCH: f32 = 440.0;
+
+/// The tone volume, from 0.0 to 1.0, used unless told otherwise.
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+/// Somewhere for a `Cpu` to send the sound it produces.
+pub trait AudioOutput {
+    /// The rate, in Hz, samples should be produced at.
+    fn sample_rate(&self) -> u32;
+
+    /// Queues mono samples, each between -1.0 and 1.0, for playback.
+    fn queue_samples(&mut self, samples: &[f32]);
+}
+
+/// The XO-CHIP pitch register value that plays a pattern at 4000 bits per
+/// second.
+pub const DEFAULT_PATTERN_PITCH: u8 = 64;
+
+/// Generates the sound played while the sound timer is non-zero.
+///
+/// This is a square wave unless an XO-CHIP program has loaded an audio
+/// pattern, in which case the pattern's 128 bits are played on loop at the
+/// rate set by the pitch register.
+pub struct ToneGenerator {
+    pitch: f32,
+    volume: f32,
+    phase: f32,
+    sample_remainder: u32,
+    buffer: Vec<f32>,
+    pattern: Option<[u8; 16]>,
+    pattern_pitch: u8,
+}
+
+impl Default for ToneGenerator {
+    fn default() -> ToneGenerator {
+        ToneGenerator::new()
+    }
+}
+
+impl ToneGenerator {
+    pub fn new() -> ToneGenerator {
+        ToneGenerator {
+            pitch: DEFAULT_PITCH,
+            volume: DEFAULT_VOLUME,
+            phase: 0.0,
+            sample_remainder: 0,
+            buffer: Vec::new(),
+            pattern: None,
+            pattern_pitch: DEFAULT_PATTERN_PITCH,
+        }
+    }
+
+    pub fn pitch(&self) -> f32 {
+        self.pitch
+    }
+
+    pub fn set_pitch(&mut self, pitch: f32) {
+        self.pitch = pitch;
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    /// Plays `pattern`, one bit per sample at the pattern rate, in place of
+    /// the square wave.
+    pub fn set_pattern(&mut self, pattern: [u8; 16]) {
+        self.pattern = Some(pattern);
+    }
+
+    /// Goes back to playing the square wave.
+    pub fn clear_pattern(&mut self) {
+        self.pattern = None;
+    }
+
+    pub fn pattern(&self) -> Option<&[u8; 16]> {
+        self.pattern.as_ref()
+    }
+
+    /// Sets the XO-CHIP pitch register, which controls the pattern rate.
+    pub fn set_pattern_pitch(&mut self, pitch: u8) {
+        self.pattern_pitch = pitch;
+    }
+
+    pub fn pattern_pitch(&self) -> u8 {
+        self.pattern_pitch
+    }
+
+    /// The rate, in bits per second, the audio pattern is played at.
+    pub fn pattern_rate(&self) -> f32 {
+        4000.0 * 2.0f32.powf((self.pattern_pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// Produces one frame of audio at `sample_rate`, silent unless `sounding`
+    /// is set. Leftover fractions of a sample are carried into the next frame
+    /// so that every 60 frames produce exactly one second of audio.
+    pub fn generate_frame(&mut self, sounding: bool, sample_rate: u32) -> &[f32] {
+        let budget = sample_rate + self.sample_remainder;
+        self.sample_remainder = budget % TIMER_FREQUENCY;
+
+        // For the square wave the phase runs over one cycle, for a pattern it
+        // runs over all 128 bits.
+        let step = match self.pattern {
+            Some(_) => self.pattern_rate() / 128.0 / sample_rate as f32,
+            None => self.pitch / sample_rate as f32,
+        };
+
+        self.buffer.clear();
+        for _ in 0..budget / TIMER_FREQUENCY {
+            let high = match self.pattern {
+                Some(ref pattern) => {
+                    let bit = (self.phase * 128.0) as usize & 127;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                },
+                None => self.phase < 0.5,
+            };
+            let sample = if !sounding {
+                0.0
+            } else if high {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.buffer.push(sample);
+
+            self.phase += step;
+            self.phase -= self.phase.floor();
+        }
+
+        &self.buffer
+    }
+}
+
+/// An `AudioOutput` writing 16-bit mono PCM to a WAV file.
+///
+/// The header's length fields are filled in by `finish`, which also happens
+/// automatically when the writer is dropped.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    data_len: u32,
+    error: Option<io::Error>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+        let file = File::create(path)?;
+        WavWriter::new(BufWriter::new(file), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(out: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let mut writer = WavWriter {
+            out,
+            sample_rate,
+            data_len: 0,
+            error: None,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let byte_rate = self.sample_rate * 2;
+        self.out.write_all(b"RIFF")?;
+        self.out.write_all(&u32_le(36 + self.data_len))?;
+        self.out.write_all(b"WAVE")?;
+        self.out.write_all(b"fmt ")?;
+        self.out.write_all(&u32_le(16))?;
+        self.out.write_all(&u16_le(1))?; // PCM
+        self.out.write_all(&u16_le(1))?; // Mono
+        self.out.write_all(&u32_le(self.sample_rate))?;
+        self.out.write_all(&u32_le(byte_rate))?;
+        self.out.write_all(&u16_le(2))?; // Block align
+        self.out.write_all(&u16_le(16))?; // Bits per sample
+        self.out.write_all(b"data")?;
+        self.out.write_all(&u32_le(self.data_len))?;
+        Ok(())
+    }
+
+    /// Fills in the header's length fields and flushes the output. Any error
+    /// hit while queueing samples is reported here.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * 32767.0) as i16;
+            self.out.write_all(&u16_le(value as u16))?;
+            self.data_len += 2;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> AudioOutput for WavWriter<W> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = self.write_samples(samples) {
+            self.error = Some(err);
+        }
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn u16_le(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn u32_le(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn carries_sample_remainder() {
+        let mut tone = ToneGenerator::new();
+        let lengths: Vec<usize> = (0..3).map(|_| tone.generate_frame(true, 1000).len()).collect();
+        assert_eq!(lengths, vec![16, 17, 17]);
+        let total: usize = (0..57).map(|_| tone.generate_frame(true, 1000).len()).sum();
+        assert_eq!(total + 50, 1000, "60 frames make exactly one second");
+    }
+
+    #[test]
+    fn silent_unless_sounding() {
+        let mut tone = ToneGenerator::new();
+        assert!(tone.generate_frame(false, 44100).iter().all(|&sample| sample == 0.0));
+        assert!(tone.generate_frame(true, 44100).iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn square_wave() {
+        let mut tone = ToneGenerator::new();
+        tone.set_pitch(150.0);
+        tone.set_volume(0.5);
+        // Four samples per cycle at 600 Hz.
+        assert_eq!(tone.generate_frame(true, 600), &[0.5, 0.5, -0.5, -0.5, 0.5, 0.5, -0.5, -0.5, 0.5, 0.5]);
+        assert_eq!(&tone.generate_frame(true, 600)[0..2], &[-0.5, -0.5], "the phase carries over");
+    }
+
+    #[test]
+    fn plays_patterns() {
+        let mut tone = ToneGenerator::new();
+        let mut pattern = [0; 16];
+        pattern[0] = 0xF0;
+        pattern[1] = 0x01;
+        tone.set_pattern(pattern);
+        // One bit per sample at the default pattern rate of 4000 bits per second.
+        let v = DEFAULT_VOLUME;
+        let samples = tone.generate_frame(true, 4000);
+        assert_eq!(&samples[0..9], &[v, v, v, v, -v, -v, -v, -v, -v]);
+        assert_eq!(samples[15], v);
+
+        tone.set_pattern_pitch(DEFAULT_PATTERN_PITCH + 48);
+        assert_eq!(tone.pattern_rate(), 8000.0, "48 steps up doubles the rate");
+    }
+
+    #[test]
+    fn writes_wav_files() {
+        let mut out = Cursor::new(Vec::new());
+        {
+            let mut writer = WavWriter::new(&mut out, 8000).unwrap();
+            writer.queue_samples(&[1.0, -1.0, 0.0]);
+            writer.queue_samples(&[2.0]);
+        }
+        let bytes = out.into_inner();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &u32_le(36 + 8), "the lengths are filled in when finished");
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(&bytes[24..28], &u32_le(8000));
+        assert_eq!(&bytes[28..32], &u32_le(16000), "two bytes a sample");
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(&bytes[40..44], &u32_le(8));
+        assert_eq!(&bytes[44..], &[0xFF, 0x7F, 0x01, 0x80, 0, 0, 0xFF, 0x7F], "clamped to 16 bits");
+    }
+}
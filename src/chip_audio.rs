@@ -0,0 +1,83 @@
+use cpal;
+use cpal::{Sample, StreamData, UnknownTypeOutputBuffer};
+use rust_chip8::AudioOutput;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Samples queued beyond this are dropped, to stop latency from building up
+/// when the emulator runs ahead of the sound card.
+const MAX_QUEUED_SAMPLES: usize = 8192;
+
+/// An `AudioOutput` playing through the default sound card.
+pub struct CpalOutput {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl CpalOutput {
+    /// Opens the default output device, returning `None` if there is no usable
+    /// sound card.
+    pub fn new() -> Option<CpalOutput> {
+        let device = cpal::default_output_device()?;
+        let format = match device.default_output_format() {
+            Ok(format) => format,
+            Err(_) => return None,
+        };
+        let event_loop = cpal::EventLoop::new();
+        let stream_id = match event_loop.build_output_stream(&device, &format) {
+            Ok(stream_id) => stream_id,
+            Err(_) => return None,
+        };
+        event_loop.play_stream(stream_id);
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let stream_queue = queue.clone();
+        let channels = format.channels as usize;
+        thread::spawn(move || {
+            event_loop.run(move |_, data| {
+                let mut queue = stream_queue.lock().unwrap();
+                match data {
+                    StreamData::Output { buffer: UnknownTypeOutputBuffer::U16(mut buffer) } => {
+                        fill(&mut buffer, channels, &mut queue);
+                    },
+                    StreamData::Output { buffer: UnknownTypeOutputBuffer::I16(mut buffer) } => {
+                        fill(&mut buffer, channels, &mut queue);
+                    },
+                    StreamData::Output { buffer: UnknownTypeOutputBuffer::F32(mut buffer) } => {
+                        fill(&mut buffer, channels, &mut queue);
+                    },
+                    _ => {}
+                }
+            });
+        });
+
+        Some(CpalOutput {
+            queue,
+            sample_rate: format.sample_rate.0,
+        })
+    }
+}
+
+fn fill<S: Sample>(buffer: &mut [S], channels: usize, queue: &mut VecDeque<f32>) {
+    for frame in buffer.chunks_mut(channels) {
+        let value = queue.pop_front().unwrap_or(0.0);
+        for out in frame.iter_mut() {
+            *out = S::from(&value);
+        }
+    }
+}
+
+impl AudioOutput for CpalOutput {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() + samples.len() > MAX_QUEUED_SAMPLES {
+            return;
+        }
+        queue.extend(samples.iter().cloned());
+    }
+}
@@ -0,0 +1,55 @@
+use gilrs;
+use gilrs::{EventType, Gilrs};
+use rust_chip8::{Button, GamepadBackend, GamepadEvent};
+
+/// A `GamepadBackend` reading every controller the system knows about.
+pub struct GilrsBackend {
+    gilrs: Gilrs,
+}
+
+impl GilrsBackend {
+    /// Starts listening for controllers, returning `None` if the platform
+    /// has no gamepad support. Controllers may still be plugged in later.
+    pub fn new() -> Option<GilrsBackend> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(GilrsBackend { gilrs }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl GamepadBackend for GilrsBackend {
+    fn next_event(&mut self) -> Option<GamepadEvent> {
+        while let Some(event) = self.gilrs.next_event() {
+            let event = match event.event {
+                EventType::ButtonPressed(button, _) => button_of(button).map(GamepadEvent::Pressed),
+                EventType::ButtonReleased(button, _) => button_of(button).map(GamepadEvent::Released),
+                EventType::Connected => Some(GamepadEvent::Connected),
+                EventType::Disconnected => Some(GamepadEvent::Disconnected),
+                _ => None,
+            };
+            if event.is_some() {
+                return event;
+            }
+        }
+        None
+    }
+}
+
+fn button_of(button: gilrs::Button) -> Option<Button> {
+    match button {
+        gilrs::Button::South => Some(Button::South),
+        gilrs::Button::East => Some(Button::East),
+        gilrs::Button::North => Some(Button::North),
+        gilrs::Button::West => Some(Button::West),
+        gilrs::Button::LeftTrigger => Some(Button::LeftShoulder),
+        gilrs::Button::RightTrigger => Some(Button::RightShoulder),
+        gilrs::Button::Select => Some(Button::Select),
+        gilrs::Button::Start => Some(Button::Start),
+        gilrs::Button::DPadUp => Some(Button::Up),
+        gilrs::Button::DPadDown => Some(Button::Down),
+        gilrs::Button::DPadLeft => Some(Button::Left),
+        gilrs::Button::DPadRight => Some(Button::Right),
+        _ => None,
+    }
+}
@@ -1,6 +1,7 @@
 use glium;
 use glium::{DisplayBuild, Surface};
-use traits::*;
+use glium::texture::{ClientFormat, MipmapsOption, RawImage2d, Texture2d, UncompressedFloatFormat};
+use rust_chip8::{AudioOutput, Chip8System, Colour, Gamepad, KeyEvent, Keymap, Keypad, Palette};
 
 #[derive(Copy, Clone)]
 struct Vertex {
@@ -10,22 +11,181 @@ struct Vertex {
 
 implement_vertex!(Vertex, position, tex_coords);
 
+/// Frontend actions bound to keys outside the hex keypad.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    /// F5
+    SaveState,
+    /// F9
+    LoadState,
+    /// F12
+    Screenshot,
+}
+
+/// How frames are blended together before being shown, to hide the flicker
+/// of sprites being erased and redrawn.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Persistence {
+    /// Show every frame as it is.
+    Off,
+    /// Like a phosphor screen: lit pixels show at once, while unlit ones
+    /// fade out, keeping this fraction of their brightness each frame.
+    Decay(f32),
+    /// Show pixels lit on either of the last two frames.
+    FrameOr,
+}
+
+/// How the screen is fitted into the window. Whatever it leaves uncovered is
+/// filled with black bars.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scaling {
+    /// The largest whole multiple of the screen's size that fits, so every
+    /// pixel is the same size. Falls back to `Aspect` in windows smaller
+    /// than the screen.
+    Integer,
+    /// As large as fits while keeping the screen's shape.
+    Aspect,
+    /// Filling the window.
+    Stretch,
+}
+
+/// The post-process shaders built in, by name. `plain.glsl` describes what
+/// they are given.
+const SHADERS: [(&'static str, &'static str); 5] = [
+    ("plain", include_str!("shaders/plain.glsl")),
+    ("scanlines", include_str!("shaders/scanlines.glsl")),
+    ("crt", include_str!("shaders/crt.glsl")),
+    ("bloom", include_str!("shaders/bloom.glsl")),
+    ("grid", include_str!("shaders/grid.glsl")),
+];
+
+/// The source of the built in shader called `name`.
+pub fn builtin_shader(name: &str) -> Option<&'static str> {
+    SHADERS.iter().find(|&&(shader, _)| shader == name).map(|&(_, source)| source)
+}
+
+/// The window's settings.
+pub struct RendererOptions {
+    pub keymap: Keymap,
+    pub gamepad: Option<Gamepad>,
+    pub palette: Palette,
+    pub persistence: Persistence,
+    /// The source of the fragment shader the screen is drawn with.
+    pub shader: String,
+    pub scaling: Scaling,
+    /// The windowed size, as a multiple of the low resolution screen's.
+    pub scale: u32,
+    pub fullscreen: bool,
+}
+
+/// The post-processing stage turning the machine's screen into the colours
+/// shown, blending in earlier frames as `Persistence` says.
+struct PersistenceStage {
+    mode: Persistence,
+    palette: Palette,
+    /// The screen on the previous frame, for `FrameOr`.
+    previous: Vec<u8>,
+    /// The colours shown on the previous frame, for `Decay`.
+    shown: Vec<[f32; 3]>,
+}
+
+impl PersistenceStage {
+    fn new(mode: Persistence, palette: Palette) -> PersistenceStage {
+        PersistenceStage { mode: mode, palette: palette, previous: Vec::new(), shown: Vec::new() }
+    }
+
+    /// Fills `pixels` with the RGBA pixels to show for the next frame.
+    /// Returns true if they would go on changing over the following frames
+    /// were the screen to stay the same.
+    fn process(&mut self, screen: &[u8], pixels: &mut Vec<u32>) -> bool {
+        pixels.clear();
+        if self.previous.len() != screen.len() {
+            // The resolution changed, so nothing earlier lines up.
+            self.previous = screen.to_vec();
+            self.shown = screen.iter().map(|&pixel| to_f32(self.palette.colour(pixel))).collect();
+        }
+
+        let palette = self.palette;
+        let settling = match self.mode {
+            Persistence::Off => {
+                pixels.extend(screen.iter().map(|&pixel| rgba(palette.colour(pixel))));
+                false
+            },
+            Persistence::FrameOr => {
+                pixels.extend(screen.iter().zip(self.previous.iter())
+                    .map(|(&pixel, &previous)| rgba(palette.colour(pixel | previous))));
+                self.previous != screen
+            },
+            Persistence::Decay(keep) => {
+                let mut settling = false;
+                for (&pixel, shown) in screen.iter().zip(self.shown.iter_mut()) {
+                    let target = to_f32(palette.colour(pixel));
+                    for i in 0..3 {
+                        let faded = target[i] + (shown[i] - target[i]) * keep;
+                        shown[i] = if pixel != 0 || (faded - target[i]).abs() < 0.5 { target[i] } else { faded };
+                        settling |= shown[i] != target[i];
+                    }
+                    pixels.push(rgba([shown[0].round() as u8, shown[1].round() as u8, shown[2].round() as u8]));
+                }
+                settling
+            },
+        };
+        self.previous.clear();
+        self.previous.extend_from_slice(screen);
+        settling
+    }
+}
+
+fn to_f32(colour: Colour) -> [f32; 3] {
+    [colour[0] as f32, colour[1] as f32, colour[2] as f32]
+}
+
+/// Packs a colour as a pixel of an RGBA texture.
+fn rgba(colour: Colour) -> u32 {
+    0xFF000000 | (colour[2] as u32) << 16 | (colour[1] as u32) << 8 | colour[0] as u32
+}
+
 pub struct GliumRenderer {
     display: Box<glium::Display>,
     indicies: Box<glium::index::NoIndices>,
     program: Box<glium::Program>,
     vertex_buffer: Box<glium::VertexBuffer<Vertex>>,
+    /// The screen as last rendered, reallocated only when the resolution
+    /// changes.
+    texture: Option<Texture2d>,
+    /// The texture's pixels, kept to save allocating them every frame.
+    pixels: Vec<u32>,
+    /// The screen as last rendered.
+    screen: Vec<u8>,
+    width: u32,
+    height: u32,
+    persistence: PersistenceStage,
+    scaling: Scaling,
+    scale: u32,
+    fullscreen: bool,
+    /// Set when F11 has been pressed, to switch in or out of fullscreen.
+    toggle_fullscreen: bool,
+    /// Set while the persistence stage needs more frames to catch up with
+    /// the screen.
+    settling: bool,
+    /// Set when the screen has been rendered during the current frame.
+    rendered: bool,
+    /// Set when the window needs drawing again without the screen having
+    /// changed, e.g. after being resized.
+    redraw: bool,
     closed: bool,
-    pressed_keys: [bool;16],
+    keymap: Keymap,
+    key_events: Vec<KeyEvent>,
+    gamepad: Option<Gamepad>,
+    audio: Option<Box<dyn AudioOutput>>,
+    hotkeys: Vec<Hotkey>,
+    rewinding: bool,
 }
 
 impl GliumRenderer {
-    pub fn new() -> GliumRenderer {
-        let display = glium::glutin::WindowBuilder::new()
-            .with_dimensions(64*8, 32*8)
-            .with_title(format!("Rust Chip8"))
-            .build_glium()
-            .unwrap();
+    /// Opens the window, failing if the shader does not compile.
+    pub fn new(options: RendererOptions) -> Result<GliumRenderer, String> {
+        let display = window(options.scale, options.fullscreen).build_glium().unwrap();
         
         let top_right = Vertex { position: [1.0, 1.0], tex_coords: [1.0, 0.0] };
         let top_left = Vertex { position: [-1.0, 1.0], tex_coords: [0.0, 0.0] };
@@ -51,56 +211,129 @@ impl GliumRenderer {
             }
         "#;
 
-        let fragment_shader_src = r#"
-            #version 140
-
-            in vec2 v_tex_coords;
-            out vec4 color;
-
-            uniform sampler2D tex;
-
-            void main() {
-                color = texture(tex, v_tex_coords);
-            }
-        "#;
-
-        let program = glium::Program::from_source(&display, vertex_shader_src, fragment_shader_src, None).unwrap();
+        let program = match glium::Program::from_source(&display, vertex_shader_src, &options.shader, None) {
+            Ok(program) => program,
+            Err(glium::ProgramCreationError::CompilationError(log)) => {
+                return Err(format!("Cannot compile shader:\n{}", log))
+            },
+            Err(err) => return Err(format!("Cannot use shader: {:?}", err)),
+        };
 
-        GliumRenderer {
+        Ok(GliumRenderer {
             display: Box::new(display),
             indicies: Box::new(indicies),
             program: Box::new(program),
             vertex_buffer: Box::new(vertex_buffer),
+            texture: None,
+            pixels: Vec::new(),
+            screen: Vec::new(),
+            width: 0,
+            height: 0,
+            persistence: PersistenceStage::new(options.persistence, options.palette),
+            scaling: options.scaling,
+            scale: options.scale,
+            fullscreen: options.fullscreen,
+            toggle_fullscreen: false,
+            settling: false,
+            rendered: false,
+            redraw: false,
             closed: false,
-            pressed_keys: [false;16],
+            keymap: options.keymap,
+            key_events: Vec::new(),
+            gamepad: options.gamepad,
+            audio: None,
+            hotkeys: Vec::new(),
+            rewinding: false,
+        })
+    }
+
+    pub fn set_audio_output(&mut self, output: Box<dyn AudioOutput>) {
+        self.audio = Some(output);
+    }
+
+    /// Returns true while the rewind key, backspace, is held.
+    pub fn is_rewinding(&self) -> bool {
+        self.rewinding
+    }
+
+    /// Returns the hotkeys pressed since the last call.
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        ::std::mem::replace(&mut self.hotkeys, Vec::new())
+    }
+
+    /// Runs the screen through the persistence stage into the texture, and
+    /// draws it.
+    fn present(&mut self) {
+        let (width, height) = (self.width, self.height);
+        self.settling = self.persistence.process(&self.screen, &mut self.pixels);
+
+        let resized = match self.texture {
+            Some(ref texture) => texture.dimensions() != (width, height),
+            None => true,
+        };
+        if resized {
+            let texture = Texture2d::empty_with_format(&*self.display, UncompressedFloatFormat::U8U8U8U8,
+                                                       MipmapsOption::NoMipmap, width, height).unwrap();
+            self.texture = Some(texture);
+        }
+
+        let image = RawImage2d {
+            data: ::std::borrow::Cow::Borrowed(&self.pixels[..]),
+            width: width,
+            height: height,
+            format: ClientFormat::U8U8U8U8,
+        };
+        let rect = glium::Rect { left: 0, bottom: 0, width: width, height: height };
+        if let Some(ref texture) = self.texture {
+            texture.write(rect, image);
         }
+        self.draw();
+    }
+
+    /// Draws the texture to the window through the shader, scaled as set.
+    fn draw(&mut self) {
+        self.redraw = false;
+        let texture = match self.texture {
+            Some(ref texture) => texture,
+            None => return,
+        };
+
+        let mut target = self.display.draw();
+        let screen = texture.dimensions();
+        let viewport = viewport(self.scaling, screen, target.get_dimensions());
+        let uniforms = uniform! {
+            tex: glium::uniforms::Sampler::new(texture)
+                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                .wrap_function(glium::uniforms::SamplerWrapFunction::Clamp),
+            source_size: [screen.0 as f32, screen.1 as f32],
+            output_size: [viewport.width as f32, viewport.height as f32],
+        };
+        let parameters = glium::DrawParameters { viewport: Some(viewport), ..Default::default() };
+
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
+        target.draw(&*self.vertex_buffer, &*self.indicies, &*self.program, &uniforms, &parameters).unwrap();
+        target.finish().unwrap();
     }
 
     fn process_events(&mut self) {
         for ev in self.display.poll_events() {
             match ev {
                 glium::glutin::Event::Closed => self.closed = true,
+                glium::glutin::Event::Resized(..) | glium::glutin::Event::Refresh => self.redraw = true,
                 glium::glutin::Event::KeyboardInput(state, _, key_opt) => {
                     let pressed = state == glium::glutin::ElementState::Pressed;
                     if let Some(key) = key_opt {
                         use glium::glutin::VirtualKeyCode;
+                        if let Some(hex_key) = self.keymap.hex_key(&key_name(key)) {
+                            let event = if pressed { KeyEvent::Pressed(hex_key) } else { KeyEvent::Released(hex_key) };
+                            self.key_events.push(event);
+                        }
                         match key {
-                            VirtualKeyCode::Key1 => self.pressed_keys[1] = pressed,
-                            VirtualKeyCode::Key2 => self.pressed_keys[2] = pressed,
-                            VirtualKeyCode::Key3 => self.pressed_keys[3] = pressed,
-                            VirtualKeyCode::Key4 => self.pressed_keys[0xC] = pressed,
-                            VirtualKeyCode::Q => self.pressed_keys[4] = pressed,
-                            VirtualKeyCode::W => self.pressed_keys[5] = pressed,
-                            VirtualKeyCode::E => self.pressed_keys[6] = pressed,
-                            VirtualKeyCode::R => self.pressed_keys[0xD] = pressed,
-                            VirtualKeyCode::A => self.pressed_keys[7] = pressed,
-                            VirtualKeyCode::S => self.pressed_keys[8] = pressed,
-                            VirtualKeyCode::D => self.pressed_keys[9] = pressed,
-                            VirtualKeyCode::F => self.pressed_keys[0xE] = pressed,
-                            VirtualKeyCode::Z => self.pressed_keys[0xA] = pressed,
-                            VirtualKeyCode::X => self.pressed_keys[0] = pressed,
-                            VirtualKeyCode::C => self.pressed_keys[0xB] = pressed,
-                            VirtualKeyCode::V => self.pressed_keys[0xF] = pressed,
+                            VirtualKeyCode::F5 if pressed => self.hotkeys.push(Hotkey::SaveState),
+                            VirtualKeyCode::F9 if pressed => self.hotkeys.push(Hotkey::LoadState),
+                            VirtualKeyCode::F11 if pressed => self.toggle_fullscreen = true,
+                            VirtualKeyCode::F12 if pressed => self.hotkeys.push(Hotkey::Screenshot),
+                            VirtualKeyCode::Back => self.rewinding = pressed,
                             _ => {} 
                         }
                     }
@@ -108,50 +341,101 @@ impl GliumRenderer {
                 _ => {}
             }
         }
+
+        if self.toggle_fullscreen {
+            self.toggle_fullscreen = false;
+            match window(self.scale, !self.fullscreen).rebuild_glium(&self.display) {
+                Ok(()) => {
+                    self.fullscreen = !self.fullscreen;
+                    self.redraw = true;
+                },
+                Err(err) => eprintln!("Cannot switch fullscreen: {:?}", err),
+            }
+        }
     }
 }
 
 
 impl Chip8System for GliumRenderer {
-    fn render(&mut self, screen: &[u8; 2048]) {
-        use glium::texture::{RawImage2d, ClientFormat, texture2d};
-        let mut screen_buf = [0xFF000000u32; 2048];
-
-        for x in 0..2048 {
-            if screen[x] != 0 { screen_buf[x] = 0xFFFFFFFFu32; }
-        }
-
-        let img = RawImage2d {
-            data: ::std::borrow::Cow::Owned(screen_buf.to_vec()),
-            width: 64,
-            height: 32,
-            format: ClientFormat::U8U8U8U8,
-        };
-
-        let tex = texture2d::Texture2d::new(&*self.display, img).unwrap();
-
-        let uniforms = uniform! {
-            tex: glium::uniforms::Sampler::new(&tex)
-                .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest),
-        };
+    fn render(&mut self, screen: &[u8], width: usize, height: usize) {
+        self.screen.clear();
+        self.screen.extend_from_slice(screen);
+        self.width = width as u32;
+        self.height = height as u32;
+        self.present();
+        self.rendered = true;
+    }
 
-        let mut target = self.display.draw(); 
-        target.clear_color(0.0, 0.0, 0.0, 1.0);
-        target.draw(&*self.vertex_buffer, &*self.indicies, &*self.program, &uniforms, &Default::default()).unwrap();
-        target.finish().unwrap();
+    fn end_frame(&mut self) {
         self.process_events();
+        if self.settling && !self.rendered {
+            self.present();
+        } else if self.redraw {
+            self.draw();
+        }
+        self.rendered = false;
     }
     
-    fn get_input(&mut self) -> Vec<u8> {
+    fn update_keypad(&mut self, keypad: &mut Keypad) {
         self.process_events();
-        let mut key_vec = Vec::new();
-        for x in 0..16u8 {
-            if self.pressed_keys[x as usize] { key_vec.push(x); }
+        if let Some(ref mut gamepad) = self.gamepad {
+            gamepad.poll(&self.keymap, &mut self.key_events);
+        }
+        for event in self.key_events.drain(..) {
+            keypad.apply(event);
         }
-        key_vec 
     }
 
     fn is_closed(&mut self) -> bool {
         self.closed
     }
+
+    fn audio_output(&mut self) -> Option<&mut dyn AudioOutput> {
+        match self.audio {
+            Some(ref mut output) => Some(&mut **output),
+            None => None,
+        }
+    }
+}
+
+/// The window to open: `scale` times the size of the low resolution screen,
+/// or covering the primary monitor.
+fn window(scale: u32, fullscreen: bool) -> glium::glutin::WindowBuilder<'static> {
+    let builder = glium::glutin::WindowBuilder::new()
+        .with_dimensions(64 * scale, 32 * scale)
+        .with_title(format!("Rust Chip8"));
+    if fullscreen {
+        builder.with_fullscreen(glium::glutin::get_primary_monitor())
+    } else {
+        builder
+    }
+}
+
+/// The part of a `window` sized window to draw a `screen` sized screen in,
+/// centred.
+fn viewport(scaling: Scaling, screen: (u32, u32), window: (u32, u32)) -> glium::Rect {
+    let (screen_width, screen_height) = (screen.0 as f32, screen.1 as f32);
+    let (width, height) = match scaling {
+        Scaling::Stretch => window,
+        Scaling::Integer if window.0 >= screen.0 && window.1 >= screen.1 => {
+            let scale = ::std::cmp::min(window.0 / screen.0, window.1 / screen.1);
+            (screen.0 * scale, screen.1 * scale)
+        },
+        Scaling::Integer | Scaling::Aspect => {
+            let scale = f32::min(window.0 as f32 / screen_width, window.1 as f32 / screen_height);
+            ((screen_width * scale) as u32, (screen_height * scale) as u32)
+        },
+    };
+    glium::Rect { left: (window.0 - width) / 2, bottom: (window.1 - height) / 2, width: width, height: height }
+}
+
+/// The name a keymap knows `key` by: `Key1` becomes `1`, and everything else
+/// is just lower cased.
+fn key_name(key: glium::glutin::VirtualKeyCode) -> String {
+    let name = format!("{:?}", key).to_lowercase();
+    if name.len() == 4 && name.starts_with("key") {
+        name[3..].to_string()
+    } else {
+        name
+    }
 }
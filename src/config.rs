@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// An error in a configuration file, with where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// A meaningful line of a configuration file.
+pub(crate) enum Entry {
+    /// `[name]`, holding what is between the brackets.
+    Section(String),
+    /// `key = value`, the value still in its TOML form.
+    Setting(String, String),
+}
+
+/// A configuration file in the small subset of TOML used for keymaps and
+/// palettes: `#` comments, `[section]` headers and `key = value` settings
+/// whose values are strings or lists of strings.
+pub(crate) struct ConfigFile {
+    name: String,
+    entries: Vec<(usize, Entry)>,
+}
+
+impl ConfigFile {
+    pub(crate) fn load(path: &Path) -> Result<ConfigFile, ConfigError> {
+        let name = path.display().to_string();
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|err| ConfigError {
+            file: name.clone(),
+            line: 0,
+            message: format!("Cannot read '{}': {}", name, err),
+        })?;
+        ConfigFile::parse(&name, &text)
+    }
+
+    pub(crate) fn parse(name: &str, text: &str) -> Result<ConfigFile, ConfigError> {
+        let mut file = ConfigFile { name: name.to_string(), entries: Vec::new() };
+        for (n, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            let entry = if line.is_empty() {
+                continue;
+            } else if line.starts_with('[') && line.ends_with(']') {
+                Entry::Section(line[1..line.len() - 1].trim().to_string())
+            } else {
+                match line.find('=') {
+                    Some(i) => Entry::Setting(line[..i].trim().to_string(), line[i + 1..].trim().to_string()),
+                    None => return Err(file.error(n + 1, "Expected 'key = value'")),
+                }
+            };
+            file.entries.push((n + 1, entry));
+        }
+        Ok(file)
+    }
+
+    /// The file's sections and settings, with their line numbers.
+    pub(crate) fn entries(&self) -> &[(usize, Entry)] {
+        &self.entries
+    }
+
+    pub(crate) fn error<S: Into<String>>(&self, line: usize, message: S) -> ConfigError {
+        ConfigError { file: self.name.clone(), line, message: message.into() }
+    }
+}
+
+/// Removes a `#` comment, ignoring any inside strings.
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &text[..i],
+            _ => {},
+        }
+    }
+    text
+}
+
+/// Parses a string in double quotes.
+pub(crate) fn parse_string(text: &str) -> Option<String> {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some(text[1..text.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+/// Parses `"string"` or `["string", ...]`.
+pub(crate) fn parse_strings(value: &str) -> Option<Vec<String>> {
+    if value.starts_with('[') && value.ends_with(']') {
+        let inner = value[1..value.len() - 1].trim();
+        if inner.is_empty() {
+            return Some(Vec::new());
+        }
+        inner.split(',').map(|name| parse_string(name.trim())).collect()
+    } else {
+        parse_string(value).map(|name| vec![name])
+    }
+}
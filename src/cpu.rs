@@ -0,0 +1,1770 @@
+use rand;
+use std::io::{Read, Write};
+use std::io;
+
+use audio::ToneGenerator;
+use display::{Display, LORES_WIDTH, LORES_HEIGHT, HIRES_WIDTH, HIRES_HEIGHT};
+use error::{CpuError, StepResult};
+use instruction::Instruction;
+use keypad::Keypad;
+use memory::{Memory, FONT_ADDRESS, BIG_FONT_ADDRESS};
+use quirks::{Profile, Quirks, DEFAULT_PROFILE};
+use random::{RandomSource, XorShiftSource};
+use registers::{Registers, STACK_SIZE};
+use state::{self, StateError, StateReader, StateWriter};
+use traits::Chip8System;
+
+/// The clock speed, in instructions per second, used unless told otherwise.
+pub const DEFAULT_CLOCK_SPEED: u32 = 720;
+
+/// The rate the delay and sound timers count down at. One frame is one tick
+/// of the timers, so this is also the frame rate in emulated time.
+pub const TIMER_FREQUENCY: u32 = 60;
+
+/// Where save states before version 4 keep the stack in memory, two bytes per
+/// return address growing upwards.
+const OLD_STACK_ADDRESS: u16 = 0xEA0;
+
+/// A CHIP-8 machine attached to a host system.
+pub struct Cpu<T: Chip8System> {
+    disp: Display,
+    mem: Memory,
+    regs: Registers,
+    system: T,
+    keypad: Keypad,
+    wait_on_input: Option<u8>,
+    wait_key: Option<u8>,
+    clock_speed: u32,
+    cycle_remainder: u32,
+    cycles: u64,
+    frames: u64,
+    tone: ToneGenerator,
+    rpl_flags: [u8; 16],
+    exited: bool,
+    xo_chip: bool,
+    profile: Profile,
+    quirks: Quirks,
+    strict: bool,
+    rom_hash: u64,
+    seed: u64,
+    rng: Box<dyn RandomSource>,
+}
+
+impl<T: Chip8System> Cpu<T> {
+    /// Creates a machine with `rom` loaded at 0x200, ready to execute it,
+    /// behaving as `DEFAULT_PROFILE`.
+    pub fn new(rom: Vec<u8>, system: T) -> Cpu<T> {
+        Cpu::new_with_profile(rom, system, DEFAULT_PROFILE)
+    }
+
+    /// Creates a machine behaving as the interpreter `profile` describes. The
+    /// XO-CHIP profile also gets 64 KiB of memory and the XO-CHIP
+    /// instructions.
+    pub fn new_with_profile(rom: Vec<u8>, system: T, profile: Profile) -> Cpu<T> {
+        let xo_chip = profile == Profile::XoChip;
+        let rom_hash = state::rom_hash(&rom);
+        let seed = rand::random();
+        let mem = if xo_chip {
+            Memory::new_xo_chip_with_rom(rom)
+        } else {
+            Memory::new_with_rom(rom)
+        };
+
+        let mut cpu = Cpu {
+            disp: Display::new(),
+            mem,
+            regs: Registers::new(),
+            system,
+            keypad: Keypad::new(),
+            wait_on_input: None,
+            wait_key: None,
+            clock_speed: DEFAULT_CLOCK_SPEED,
+            cycle_remainder: 0,
+            cycles: 0,
+            frames: 0,
+            tone: ToneGenerator::new(),
+            rpl_flags: [0; 16],
+            exited: false,
+            xo_chip,
+            profile,
+            quirks: profile.quirks(),
+            strict: false,
+            rom_hash,
+            seed,
+            rng: Box::new(XorShiftSource::new(seed)),
+        };
+        cpu.set_quirks(profile.quirks());
+        cpu
+    }
+
+    /// The profile the machine was created with.
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Returns true if invalid instructions stop `run_frame` with an error.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// In strict mode `run_frame` stops at the first invalid instruction and
+    /// returns the error. Otherwise, the default, invalid instructions are
+    /// passed to `Chip8System::report_error` and skipped.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Overrides the quirks of the machine's profile.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+        self.disp.set_clip_sprites(quirks.clip_sprites);
+    }
+
+    /// Returns true if the XO-CHIP instructions are enabled.
+    pub fn is_xo_chip(&self) -> bool {
+        self.xo_chip
+    }
+
+    /// The number of instructions executed per second of emulated time.
+    pub fn clock_speed(&self) -> u32 {
+        self.clock_speed
+    }
+
+    pub fn set_clock_speed(&mut self, instructions_per_second: u32) {
+        self.clock_speed = instructions_per_second;
+        self.cycle_remainder = 0;
+    }
+
+    /// The number of instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn tone(&self) -> &ToneGenerator {
+        &self.tone
+    }
+
+    /// The generator for the sound timer's tone, for adjusting pitch and volume.
+    pub fn tone_mut(&mut self) -> &mut ToneGenerator {
+        &mut self.tone
+    }
+
+    /// The number of frames run so far. Each frame is 1/60th of a second of
+    /// emulated time.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.disp
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.mem
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.regs
+    }
+
+    /// The return addresses on the stack, innermost last. Only the last
+    /// `STACK_SIZE` pushed are kept.
+    pub fn stack(&self) -> Vec<u16> {
+        let depth = self.regs.stack;
+        (depth.saturating_sub(STACK_SIZE as u16)..depth)
+            .map(|n| self.regs.call_stack[n as usize % STACK_SIZE])
+            .collect()
+    }
+
+    pub fn system(&self) -> &T {
+        &self.system
+    }
+
+    pub fn system_mut(&mut self) -> &mut T {
+        &mut self.system
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    /// The keypad, for hosts that push key events directly rather than
+    /// through `Chip8System::update_keypad`.
+    pub fn keypad_mut(&mut self) -> &mut Keypad {
+        &mut self.keypad
+    }
+
+    /// The register an FX0A instruction is waiting to store a key in, if any.
+    pub fn waiting_for_key(&self) -> Option<u8> {
+        self.wait_on_input
+    }
+
+    /// The SUPER-CHIP RPL user flags saved and restored by FX75 and FX85.
+    pub fn rpl_flags(&self) -> &[u8; 16] {
+        &self.rpl_flags
+    }
+
+    /// Returns true if the program has exited with 00FD, or if the next
+    /// instruction is a jump to itself, the usual way for a program to stop
+    /// once it has finished. Jumps only reach the first 4 KiB, so an XO-CHIP
+    /// program can't halt this way above that, and its `F000 NNNN` only
+    /// loads the index register rather than jumping.
+    pub fn is_halted(&self) -> bool {
+        if self.exited {
+            return true;
+        }
+        let pc = self.regs.address;
+        let next = self.mem.read_word(pc.wrapping_add(2));
+        let instruction = Instruction::decode(self.mem.read_word(pc), next, self.xo_chip);
+        self.wait_on_input.is_none() && instruction == Instruction::Jp(pc)
+    }
+
+    /// The seed CXNN's random numbers were last seeded with. This is random
+    /// unless set with `set_seed`.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Reseeds the random number generator, so that CXNN produces the same
+    /// sequence for the same seed.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.reseed(seed);
+    }
+
+    /// Replaces the generator CXNN draws from, seeding it with the current
+    /// seed.
+    pub fn set_random_source(&mut self, mut source: Box<dyn RandomSource>) {
+        source.reseed(self.seed);
+        self.rng = source;
+    }
+
+    /// A hash of the ROM the machine was created with, as stored in save
+    /// states.
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// Writes the whole machine state, apart from the host, in the versioned
+    /// save state format.
+    pub fn save_state<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut state = StateWriter::new(self.rom_hash, self.profile);
+
+        let quirks = self.quirks;
+        for &quirk in [quirks.shift_uses_vy, quirks.load_store_increments_index, quirks.jump_uses_vx,
+                       quirks.clip_sprites, quirks.logic_resets_vf].iter() {
+            state.bool(quirk);
+        }
+
+        state.bytes(&self.regs.data);
+        state.u16(self.regs.address);
+        state.u16(self.regs.stack);
+        state.u16(self.regs.index);
+        state.u8(self.regs.delay_timer);
+        state.u8(self.regs.sound_timer);
+        state.u8(self.wait_on_input.unwrap_or(0xFF));
+        state.u8(self.wait_key.unwrap_or(0xFF));
+
+        state.u32(self.clock_speed);
+        state.u32(self.cycle_remainder);
+        state.u64(self.cycles);
+        state.u64(self.frames);
+        state.bytes(&self.rpl_flags);
+        state.bool(self.exited);
+
+        state.bool(self.tone.pattern().is_some());
+        state.bytes(self.tone.pattern().unwrap_or(&[0; 16]));
+        state.u8(self.tone.pattern_pitch());
+
+        state.bool(self.disp.is_hires());
+        state.u8(self.disp.selected_planes());
+        state.bytes(self.disp.screen());
+
+        state.u32(self.mem.size() as u32);
+        state.bytes(self.mem.bytes());
+
+        let rng = self.rng.state();
+        state.u64(self.seed);
+        state.u8(self.rng.name().len() as u8);
+        state.bytes(self.rng.name().as_bytes());
+        state.u8(rng.len() as u8);
+        state.bytes(&rng);
+
+        for &addr in self.regs.call_stack.iter() {
+            state.u16(addr);
+        }
+        state.bool(quirks.load_store_increments_index_by_x);
+
+        out.write_all(&state.into_bytes())
+    }
+
+    /// Restores a state written by `save_state`. States saved from a
+    /// different ROM or profile are refused, and the machine is left as it
+    /// was if anything is wrong with the state.
+    pub fn load_state<R: Read>(&mut self, input: &mut R) -> Result<(), StateError> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        let (mut state, version) = StateReader::new(&bytes, self.rom_hash, self.profile)?;
+
+        let mut quirks = Quirks {
+            shift_uses_vy: state.bool()?,
+            load_store_increments_index: state.bool()?,
+            jump_uses_vx: state.bool()?,
+            clip_sprites: state.bool()?,
+            logic_resets_vf: state.bool()?,
+            load_store_increments_index_by_x: false,
+        };
+
+        let mut regs = Registers::new();
+        regs.data.copy_from_slice(state.bytes(16)?);
+        regs.address = state.u16()?;
+        regs.stack = state.u16()?;
+        regs.index = state.u16()?;
+        regs.delay_timer = state.u8()?;
+        regs.sound_timer = state.u8()?;
+        let wait_on_input = match state.u8()? {
+            0xFF => None,
+            reg if reg < 16 => Some(reg),
+            _ => return Err(StateError::Corrupt),
+        };
+        // Version 1 predates waiting for FX0A's key to be released.
+        let wait_key = if version >= 2 { state.u8()? } else { 0xFF };
+        let wait_key = match wait_key {
+            0xFF => None,
+            key if key < 16 => Some(key),
+            _ => return Err(StateError::Corrupt),
+        };
+
+        let clock_speed = state.u32()?;
+        let cycle_remainder = state.u32()?;
+        let cycles = state.u64()?;
+        let frames = state.u64()?;
+        let mut rpl_flags = [0; 16];
+        rpl_flags.copy_from_slice(state.bytes(16)?);
+        let exited = state.bool()?;
+
+        let has_pattern = state.bool()?;
+        let mut pattern = [0; 16];
+        pattern.copy_from_slice(state.bytes(16)?);
+        let pattern_pitch = state.u8()?;
+
+        let hires = state.bool()?;
+        let planes = state.u8()?;
+        let (width, height) = if hires { (HIRES_WIDTH, HIRES_HEIGHT) } else { (LORES_WIDTH, LORES_HEIGHT) };
+        let screen = state.bytes(width * height)?;
+
+        if state.u32()? as usize != self.mem.size() {
+            return Err(StateError::Corrupt);
+        }
+        let memory = state.bytes(self.mem.size())?;
+        // Version 3 added the random number generator, which older states
+        // leave running on from where it is, and version 5 its name.
+        let rng = if version >= 3 {
+            let seed = state.u64()?;
+            if version >= 5 {
+                let len = state.u8()? as usize;
+                let name = String::from_utf8_lossy(state.bytes(len)?).into_owned();
+                if name != self.rng.name() {
+                    return Err(StateError::RandomMismatch(name));
+                }
+            }
+            let len = state.u8()? as usize;
+            Some((seed, state.bytes(len)?))
+        } else {
+            None
+        };
+        // Version 4 moved the stack out of memory. Older states keep it
+        // there, with the stack pointer an address.
+        if version >= 4 {
+            for addr in regs.call_stack.iter_mut() {
+                *addr = state.u16()?;
+            }
+        } else {
+            let depth = regs.stack.wrapping_sub(OLD_STACK_ADDRESS) / 2;
+            for n in depth.saturating_sub(STACK_SIZE as u16)..depth {
+                let addr = OLD_STACK_ADDRESS as usize + n as usize * 2;
+                let low = memory[addr % memory.len()] as u16;
+                let high = memory[(addr + 1) % memory.len()] as u16;
+                regs.call_stack[n as usize % STACK_SIZE] = low | (high << 8);
+            }
+            regs.stack = depth;
+        }
+        // Version 6 added advancing I by X, which no profile did before.
+        if version >= 6 {
+            quirks.load_store_increments_index_by_x = state.bool()?;
+        }
+        state.finish()?;
+        if let Some((seed, rng)) = rng {
+            // Restored before anything else is committed, as it can still
+            // fail, in which case `set_state` leaves the generator alone.
+            if !self.rng.set_state(rng) {
+                return Err(StateError::Corrupt);
+            }
+            self.seed = seed;
+        }
+
+        self.set_quirks(quirks);
+        self.regs = regs;
+        self.wait_on_input = wait_on_input;
+        self.wait_key = wait_key;
+        self.clock_speed = clock_speed;
+        self.cycle_remainder = cycle_remainder;
+        self.cycles = cycles;
+        self.frames = frames;
+        self.rpl_flags = rpl_flags;
+        self.exited = exited;
+        if has_pattern {
+            self.tone.set_pattern(pattern);
+        } else {
+            self.tone.clear_pattern();
+        }
+        self.tone.set_pattern_pitch(pattern_pitch);
+        self.disp.restore(hires, planes, screen);
+        self.mem.restore(memory);
+        Ok(())
+    }
+
+    /// Runs 1/60th of a second worth of instructions, plays that frame's
+    /// audio, ticks the timers and hands the screen to the host. Returns true
+    /// once the host has been closed, or an error if an invalid instruction
+    /// was hit in strict mode.
+    ///
+    /// When the clock speed is not a multiple of 60 the leftover instructions
+    /// are carried over, so that exactly `clock_speed` instructions run for
+    /// every 60 frames.
+    pub fn run_frame(&mut self) -> Result<bool, CpuError> {
+        for _ in 0..self.frame_budget() {
+            if let Err(err) = self.step() {
+                if self.strict {
+                    return Err(err);
+                }
+                self.system.report_error(&err);
+            }
+        }
+
+        Ok(self.end_frame())
+    }
+
+    /// The number of instructions to run in the next frame. Call once per
+    /// frame when stepping through a frame by hand, then call `end_frame`.
+    pub fn frame_budget(&mut self) -> u32 {
+        let budget = self.clock_speed + self.cycle_remainder;
+        self.cycle_remainder = budget % TIMER_FREQUENCY;
+        budget / TIMER_FREQUENCY
+    }
+
+    /// Plays the frame's audio, ticks the timers, hands the screen to the
+    /// host if it has changed and lets the host update the keypad. Returns
+    /// true once the host has been closed.
+    pub fn end_frame(&mut self) -> bool {
+        self.play_audio();
+        self.tick_timers();
+        self.rng.end_frame();
+        self.frames += 1;
+        if self.disp.is_dirty() {
+            self.system.render(self.disp.screen(), self.disp.width(), self.disp.height());
+            self.disp.mark_clean();
+        }
+        self.system.end_frame();
+        self.system.update_keypad(&mut self.keypad);
+
+        self.system.is_closed()
+    }
+
+    /// Hands the screen to the host again without running anything, e.g.
+    /// while rewinding, and lets it handle its events. Returns true once the
+    /// host has been closed.
+    pub fn refresh(&mut self) -> bool {
+        self.system.render(self.disp.screen(), self.disp.width(), self.disp.height());
+        self.disp.mark_clean();
+        self.system.end_frame();
+        self.system.is_closed()
+    }
+
+    fn play_audio(&mut self) {
+        let sounding = self.regs.sound_timer != 0;
+        if let Some(output) = self.system.audio_output() {
+            let sample_rate = output.sample_rate();
+            output.queue_samples(self.tone.generate_frame(sounding, sample_rate));
+        }
+    }
+
+    /// Decrements the delay and sound timers.
+    pub fn tick_timers(&mut self) {
+        if self.regs.delay_timer != 0 { self.regs.delay_timer -= 1; }
+        if self.regs.sound_timer != 0 { self.regs.sound_timer -= 1; }
+    }
+
+    /// Executes a single instruction. While waiting on FX0A this checks the
+    /// keypad instead, and once the program has exited it does nothing.
+    ///
+    /// Invalid instructions are skipped over, returning an error describing
+    /// them.
+    pub fn step(&mut self) -> StepResult {
+        if self.exited {
+            return Ok(());
+        }
+
+        self.cycles += 1;
+        if let Some(reg) = self.wait_on_input {
+            self.poll_key_wait(reg);
+            return Ok(());
+        }
+
+        let address = self.regs.address;
+        let opcode = self.read_opcode();
+        let next = self.mem.read_word(self.regs.address);
+        match Instruction::decode(opcode, next, self.xo_chip) {
+            Instruction::Cls => self.clear_screen(),
+            Instruction::Ret => self.ret(),
+            Instruction::Scd(n) => self.scroll_down(n),
+            Instruction::Scu(n) => self.scroll_up(n),
+            Instruction::Scr => self.scroll_right(),
+            Instruction::Scl => self.scroll_left(),
+            Instruction::Exit => self.exit(),
+            Instruction::Low => self.set_hires(false),
+            Instruction::High => self.set_hires(true),
+            Instruction::Sys(_) => return Err(CpuError::MachineCodeCall { address, opcode }),
+            Instruction::Jp(addr) => self.jump(addr),
+            Instruction::Call(addr) => self.sub(addr),
+            Instruction::SeByte(x, nn) => self.skip_if(x, nn),
+            Instruction::SneByte(x, nn) => self.skip_if_not(x, nn),
+            Instruction::SeReg(x, y) => self.skip_if_reg(x, y),
+            Instruction::Save(x, y) => self.store_range(x, y),
+            Instruction::Load(x, y) => self.fill_range(x, y),
+            Instruction::LdByte(x, nn) => self.set(x, nn),
+            Instruction::AddByte(x, nn) => self.add(x, nn),
+            Instruction::LdReg(x, y) => self.set_reg(x, y),
+            Instruction::Or(x, y) => self.or_reg(x, y),
+            Instruction::And(x, y) => self.and_reg(x, y),
+            Instruction::Xor(x, y) => self.xor_reg(x, y),
+            Instruction::AddReg(x, y) => self.add_reg(x, y),
+            Instruction::Sub(x, y) => self.cmp_reg(x, y),
+            Instruction::Shr(x, y) => self.shift_right_reg(x, y),
+            Instruction::Subn(x, y) => self.sub_reg(x, y),
+            Instruction::Shl(x, y) => self.shift_left_reg(x, y),
+            Instruction::SneReg(x, y) => self.skip_if_not_reg(x, y),
+            Instruction::LdI(addr) => self.set_index(addr),
+            Instruction::JpV0(addr) => self.jump_offset(addr),
+            Instruction::Rnd(x, nn) => self.random(x, nn),
+            Instruction::Drw(x, y, n) => self.draw_sprite(x, y, n),
+            Instruction::Skp(x) => self.skip_if_key(x),
+            Instruction::Sknp(x) => self.skip_if_not_key(x),
+            Instruction::LdLongI(addr) => self.set_long_index(addr),
+            Instruction::Plane(n) => self.select_planes(n),
+            Instruction::Audio => self.load_audio_pattern(),
+            Instruction::LdVxDt(x) => self.set_from_delay_timer(x),
+            Instruction::LdVxK(x) => self.wait_for_key(x),
+            Instruction::LdDtVx(x) => self.set_delay_timer(x),
+            Instruction::LdStVx(x) => self.set_sound_timer(x),
+            Instruction::AddIVx(x) => self.add_to_index(x),
+            Instruction::LdFVx(x) => self.set_index_to_character(x),
+            Instruction::LdHfVx(x) => self.set_index_to_big_character(x),
+            Instruction::LdBVx(x) => self.store_bcd(x),
+            Instruction::Pitch(x) => self.set_pattern_pitch(x),
+            Instruction::LdIVx(x) => self.store_to_index(x),
+            Instruction::LdVxI(x) => self.fill_from_index(x),
+            Instruction::LdRVx(x) => self.store_rpl_flags(x),
+            Instruction::LdVxR(x) => self.fill_from_rpl_flags(x),
+            Instruction::Unknown(_) => return Err(CpuError::UnknownOpcode { address, opcode }),
+        };
+
+        Ok(())
+    }
+
+    fn clear_screen(&mut self) {
+        self.disp.clear_screen();
+    }
+
+    fn scroll_down(&mut self, rows: u8) {
+        self.disp.scroll_down(rows as usize);
+    }
+
+    fn scroll_up(&mut self, rows: u8) {
+        self.disp.scroll_up(rows as usize);
+    }
+
+    fn scroll_right(&mut self) {
+        self.disp.scroll_right(4);
+    }
+
+    fn scroll_left(&mut self) {
+        self.disp.scroll_left(4);
+    }
+
+    fn exit(&mut self) {
+        self.exited = true;
+    }
+
+    fn set_hires(&mut self, hires: bool) {
+        self.disp.set_hires(hires);
+    }
+
+    /// Skips the next instruction, which in XO-CHIP mode may be the four byte
+    /// F000 NNNN.
+    fn skip_next(&mut self) {
+        let next = self.mem.read_word(self.regs.address);
+        let len = if self.xo_chip && next == 0xF000 { 4 } else { 2 };
+        self.regs.address = self.regs.address.wrapping_add(len);
+    }
+
+    fn skip_if(&mut self, reg: u8, value: u8) {
+        if self.regs.get_data(reg) == value {
+            self.skip_next();
+        }
+    }
+
+    fn skip_if_not(&mut self, reg: u8, value: u8) {
+        if self.regs.get_data(reg) != value {
+            self.skip_next();
+        }
+    }
+
+    fn skip_if_reg(&mut self, reg_a: u8, reg_b: u8) {
+        if self.regs.get_data(reg_a) == self.regs.get_data(reg_b) {
+            self.skip_next();
+        }
+    }
+
+    fn set(&mut self, reg: u8, value: u8) {
+        self.regs.set_data(reg, value);
+    }
+
+    fn add(&mut self, reg: u8, value: u8) {
+        let reg_val = self.regs.get_data(reg);
+        self.regs.set_data(reg, reg_val.wrapping_add(value));
+    }
+
+    fn set_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val = self.regs.get_data(reg_b);
+        self.regs.set_data(reg_a, val);
+    }
+
+    fn or_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val_left = self.regs.get_data(reg_a);
+        let val_right = self.regs.get_data(reg_b);
+        self.regs.set_data(reg_a, val_left | val_right);
+        self.logic_vf_reset();
+    }
+
+    fn and_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val_left = self.regs.get_data(reg_a);
+        let val_right = self.regs.get_data(reg_b);
+        self.regs.set_data(reg_a, val_left & val_right);
+        self.logic_vf_reset();
+    }
+
+    fn xor_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val_left = self.regs.get_data(reg_a);
+        let val_right = self.regs.get_data(reg_b);
+        self.regs.set_data(reg_a, val_left ^ val_right);
+        self.logic_vf_reset();
+    }
+
+    fn logic_vf_reset(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.regs.set_data(0xF, 0);
+        }
+    }
+
+    /// The register 8XY6 and 8XYE shift, which depends on the quirks.
+    fn shift_source(&self, reg_a: u8, reg_b: u8) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.regs.get_data(reg_b)
+        } else {
+            self.regs.get_data(reg_a)
+        }
+    }
+
+    // The arithmetic instructions write VF after the result, so that when VF
+    // is also the destination it ends up holding the flag.
+
+    fn add_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val_left = self.regs.get_data(reg_b);
+        let val_right = self.regs.get_data(reg_a);
+        let carry = (val_left as u16) + (val_right as u16) > 255;
+
+        self.regs.set_data(reg_a, val_left.wrapping_add(val_right));
+        self.regs.set_data(0xF, if carry { 1 } else { 0 });
+    }
+
+    fn cmp_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val_left = self.regs.get_data(reg_a);
+        let val_right = self.regs.get_data(reg_b);
+        let no_borrow = val_left >= val_right;
+
+        self.regs.set_data(reg_a, val_left.wrapping_sub(val_right));
+        self.regs.set_data(0xF, if no_borrow { 1 } else { 0 });
+    }
+
+    fn shift_right_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val = self.shift_source(reg_a, reg_b);
+        self.regs.set_data(reg_a, val.wrapping_shr(1));
+        self.regs.set_data(0xF, val & 1);
+    }
+
+    fn sub_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val_left = self.regs.get_data(reg_b);
+        let val_right = self.regs.get_data(reg_a);
+        let no_borrow = val_left >= val_right;
+
+        self.regs.set_data(reg_a, val_left.wrapping_sub(val_right));
+        self.regs.set_data(0xF, if no_borrow { 1 } else { 0 });
+    }
+
+    fn shift_left_reg(&mut self, reg_a: u8, reg_b: u8) {
+        let val = self.shift_source(reg_a, reg_b);
+        self.regs.set_data(reg_a, val.wrapping_shl(1));
+        self.regs.set_data(0xF, (val & 0x80) >> 7);
+    }
+
+    fn skip_if_not_reg(&mut self, reg_a: u8, reg_b: u8) {
+        if self.regs.get_data(reg_a) != self.regs.get_data(reg_b) {
+            self.skip_next();
+        }
+    }
+
+    fn set_index(&mut self, value: u16) {
+        self.regs.index = value;
+    }
+
+    fn jump_offset(&mut self, addr: u16) {
+        let reg = if self.quirks.jump_uses_vx { (addr >> 8) as u8 } else { 0 };
+        self.regs.address = (self.regs.get_data(reg) as u16).wrapping_add(addr);
+    }
+
+    fn random(&mut self, reg: u8, value: u8) {
+        let random = self.rng.next_byte(&self.mem);
+        self.regs.set_data(reg, random & value);
+    }
+
+    /// Draws an 8xN sprite, or a SUPER-CHIP 16x16 sprite when N is 0, on each
+    /// selected plane. Sprite data for plane 2 follows that for plane 1.
+    ///
+    /// In SUPER-CHIP high resolution mode VF is set to the number of rows that
+    /// collided rather than just 1.
+    fn draw_sprite(&mut self, reg_a: u8, reg_b: u8, rows: u8) {
+        let x = self.regs.get_data(reg_a) as usize % self.disp.width();
+        let y = self.regs.get_data(reg_b) as usize % self.disp.height();
+        let wide = rows == 0;
+        let height = if wide { 16 } else { rows as usize };
+
+        let mut addr = self.regs.index;
+        let mut collided_rows = 0u8;
+        for plane in [1u8, 2].iter().cloned() {
+            if self.disp.selected_planes() & plane == 0 {
+                continue;
+            }
+
+            for n in 0..height {
+                let flipped = if wide {
+                    let line = self.mem.read_word(addr);
+                    addr = addr.wrapping_add(2);
+                    self.disp.draw_wide_line(plane, line, x, y + n)
+                } else {
+                    let line = self.mem.read(addr);
+                    addr = addr.wrapping_add(1);
+                    self.disp.draw_line(plane, line, x, y + n)
+                };
+                if flipped { collided_rows += 1; }
+            }
+        }
+
+        if self.disp.is_hires() && !self.xo_chip {
+            // SUPER-CHIP 1.1 also counts the rows clipped off the bottom.
+            let clipped_rows = if self.quirks.clip_sprites {
+                (y + height).saturating_sub(self.disp.height()) as u8
+            } else {
+                0
+            };
+            self.regs.set_data(0xF, collided_rows + clipped_rows);
+        } else {
+            self.regs.set_data(0xF, if collided_rows != 0 { 1 } else { 0 });
+        }
+    }
+
+    fn skip_if_key(&mut self, reg: u8) {
+        let key = self.regs.get_data(reg);
+        if self.keypad.is_held(key) {
+            self.skip_next();
+        }
+    }
+
+    fn skip_if_not_key(&mut self, reg: u8) {
+        let key = self.regs.get_data(reg);
+        if !self.keypad.is_held(key) {
+            self.skip_next();
+        }
+    }
+
+    fn set_from_delay_timer(&mut self, reg: u8) {
+        let val = self.regs.delay_timer;
+        self.regs.set_data(reg, val);
+    }
+
+    fn wait_for_key(&mut self, reg: u8) {
+        self.wait_on_input = Some(reg);
+        self.wait_key = None;
+        self.keypad.clear_presses();
+    }
+
+    /// Like the VIP, FX0A only finishes once a key pressed during the wait
+    /// has been released again, so a held key is not read twice.
+    fn poll_key_wait(&mut self, reg: u8) {
+        if self.wait_key.is_none() {
+            self.wait_key = self.keypad.take_press();
+        }
+        if let Some(key) = self.wait_key {
+            if !self.keypad.is_held(key) {
+                self.regs.set_data(reg, key);
+                self.wait_on_input = None;
+                self.wait_key = None;
+            }
+        }
+    }
+
+    fn set_delay_timer(&mut self, reg: u8) {
+        self.regs.delay_timer = self.regs.get_data(reg);
+    }
+
+    fn set_sound_timer(&mut self, reg: u8) {
+        self.regs.sound_timer = self.regs.get_data(reg);
+    }
+
+    fn add_to_index(&mut self, reg: u8) {
+        self.regs.index = self.regs.index.wrapping_add(self.regs.get_data(reg) as u16);
+    }
+
+    fn set_index_to_character(&mut self, reg: u8) {
+        self.regs.index = FONT_ADDRESS + (self.regs.get_data(reg) & 0xF) as u16 * 5
+    }
+
+    fn set_index_to_big_character(&mut self, reg: u8) {
+        self.regs.index = BIG_FONT_ADDRESS + (self.regs.get_data(reg) & 0xF) as u16 * 10
+    }
+
+    fn store_bcd(&mut self, reg: u8) {
+        let val = self.regs.get_data(reg);
+
+        let hundreds = val / 100;
+        let tens = (val % 100) / 10;
+        let ones = val % 10;
+
+        self.mem.write(self.regs.index, hundreds);
+        self.mem.write(self.regs.index.wrapping_add(1), tens);
+        self.mem.write(self.regs.index.wrapping_add(2), ones);
+    }
+
+    fn store_to_index(&mut self, reg: u8) {
+        for n in 0..(reg & 0xF) + 1 {
+            self.mem.write(self.regs.index.wrapping_add(n as u16), self.regs.get_data(n));
+        }
+        self.load_store_index_increment(reg);
+    }
+
+    fn fill_from_index(&mut self, reg: u8) {
+        for n in 0..(reg & 0xF) + 1 {
+            let val = self.mem.read(self.regs.index.wrapping_add(n as u16));
+            self.regs.set_data(n, val);
+        }
+        self.load_store_index_increment(reg);
+    }
+
+    fn load_store_index_increment(&mut self, reg: u8) {
+        if self.quirks.load_store_increments_index {
+            let skipped = if self.quirks.load_store_increments_index_by_x { 0 } else { 1 };
+            self.regs.index = self.regs.index.wrapping_add((reg & 0xF) as u16 + skipped);
+        }
+    }
+
+    /// Yields the registers from `reg_a` to `reg_b` inclusive, counting down if
+    /// `reg_a` is the larger.
+    fn reg_range(reg_a: u8, reg_b: u8) -> Vec<u8> {
+        if reg_a <= reg_b {
+            (reg_a..reg_b + 1).collect()
+        } else {
+            (reg_b..reg_a + 1).rev().collect()
+        }
+    }
+
+    fn store_range(&mut self, reg_a: u8, reg_b: u8) {
+        for (n, reg) in Self::reg_range(reg_a, reg_b).into_iter().enumerate() {
+            self.mem.write(self.regs.index.wrapping_add(n as u16), self.regs.get_data(reg));
+        }
+    }
+
+    fn fill_range(&mut self, reg_a: u8, reg_b: u8) {
+        for (n, reg) in Self::reg_range(reg_a, reg_b).into_iter().enumerate() {
+            let val = self.mem.read(self.regs.index.wrapping_add(n as u16));
+            self.regs.set_data(reg, val);
+        }
+    }
+
+    fn set_long_index(&mut self, addr: u16) {
+        self.regs.index = addr;
+        self.regs.address = self.regs.address.wrapping_add(2);
+    }
+
+    fn select_planes(&mut self, planes: u8) {
+        self.disp.select_planes(planes);
+    }
+
+    fn load_audio_pattern(&mut self) {
+        let mut pattern = [0; 16];
+        for (n, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.mem.read(self.regs.index.wrapping_add(n as u16));
+        }
+        self.tone.set_pattern(pattern);
+    }
+
+    fn set_pattern_pitch(&mut self, reg: u8) {
+        let pitch = self.regs.get_data(reg);
+        self.tone.set_pattern_pitch(pitch);
+    }
+
+    fn store_rpl_flags(&mut self, reg: u8) {
+        for n in 0..(reg & 0xF) + 1 {
+            self.rpl_flags[n as usize] = self.regs.get_data(n);
+        }
+    }
+
+    fn fill_from_rpl_flags(&mut self, reg: u8) {
+        for n in 0..(reg & 0xF) + 1 {
+            let val = self.rpl_flags[n as usize];
+            self.regs.set_data(n, val);
+        }
+    }
+
+    fn read_opcode(&mut self) -> u16 {
+        let word = self.mem.read_word(self.regs.address);
+        self.regs.address = self.regs.address.wrapping_add(2);
+        word
+    }
+
+    fn jump(&mut self, address: u16) {
+        self.regs.address = address;
+    }
+
+    fn sub(&mut self, address: u16) {
+        let return_addr = self.regs.address;
+        self.push_addr(return_addr);
+        self.regs.address = address;
+    }
+
+    fn ret(&mut self) {
+        self.regs.address = self.pop_addr();
+    }
+
+    fn push_addr(&mut self, address: u16) {
+        self.regs.call_stack[self.regs.stack as usize % STACK_SIZE] = address;
+        self.regs.stack = self.regs.stack.wrapping_add(1);
+    }
+
+    fn pop_addr(&mut self) -> u16 {
+        self.regs.stack = self.regs.stack.wrapping_sub(1);
+        self.regs.call_stack[self.regs.stack as usize % STACK_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::CpuError;
+    use memory::MEMORY_SIZE;
+    use random::{RandomMode, VipRandom};
+    use quirks::Profile;
+    use state::StateError;
+    use traits::Chip8System;
+
+    struct TestSystem {
+        errors: Vec<CpuError>,
+        renders: usize,
+        frames_ended: usize,
+    }
+
+    impl Chip8System for TestSystem {
+        fn render(&mut self, _: &[u8], _: usize, _: usize) {
+            self.renders += 1;
+        }
+
+        fn end_frame(&mut self) {
+            self.frames_ended += 1;
+        }
+
+        fn is_closed(&mut self) -> bool {
+            false
+        }
+
+        fn report_error(&mut self, error: &CpuError) {
+            self.errors.push(error.clone());
+        }
+    }
+
+    fn rom(program: &[u16]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for word in program {
+            bytes.push((word >> 8) as u8);
+            bytes.push(*word as u8);
+        }
+        bytes
+    }
+
+    fn new_cpu_with_profile(program: &[u16], profile: Profile) -> Cpu<TestSystem> {
+        Cpu::new_with_profile(rom(program), TestSystem { errors: Vec::new(), renders: 0, frames_ended: 0 }, profile)
+    }
+
+    fn new_cpu(program: &[u16]) -> Cpu<TestSystem> {
+        new_cpu_with_profile(program, Profile::SuperChip)
+    }
+
+    /// Runs one step per instruction in `program`.
+    fn run(program: &[u16]) -> Cpu<TestSystem> {
+        let mut cpu = new_cpu(program);
+        for _ in 0..program.len() {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    fn v(cpu: &Cpu<TestSystem>, reg: u8) -> u8 {
+        cpu.registers().get_data(reg)
+    }
+
+    #[test]
+    fn clear_screen() {
+        let cpu = run(&[0xA000, 0xD005, 0x00E0]);
+        assert!(cpu.display().screen().iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn jump() {
+        let cpu = run(&[0x1234]);
+        assert_eq!(cpu.registers().address, 0x234);
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut cpu = new_cpu(&[0x2206, 0x6001, 0x1204, 0x00EE]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x206);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x202);
+        cpu.step().unwrap();
+        assert_eq!(v(&cpu, 0), 1);
+    }
+
+    #[test]
+    fn skip_if_equal() {
+        let cpu = run(&[0x6012, 0x3012]);
+        assert_eq!(cpu.registers().address, 0x206);
+        let cpu = run(&[0x6012, 0x3013]);
+        assert_eq!(cpu.registers().address, 0x204);
+    }
+
+    #[test]
+    fn skip_if_not_equal() {
+        let cpu = run(&[0x6012, 0x4013]);
+        assert_eq!(cpu.registers().address, 0x206);
+        let cpu = run(&[0x6012, 0x4012]);
+        assert_eq!(cpu.registers().address, 0x204);
+    }
+
+    #[test]
+    fn skip_if_registers_equal() {
+        let cpu = run(&[0x6005, 0x6105, 0x5010]);
+        assert_eq!(cpu.registers().address, 0x208);
+        let cpu = run(&[0x6005, 0x6106, 0x5010]);
+        assert_eq!(cpu.registers().address, 0x206);
+    }
+
+    #[test]
+    fn skip_if_registers_not_equal() {
+        let cpu = run(&[0x6005, 0x6106, 0x9010]);
+        assert_eq!(cpu.registers().address, 0x208);
+        let cpu = run(&[0x6005, 0x6105, 0x9010]);
+        assert_eq!(cpu.registers().address, 0x206);
+    }
+
+    #[test]
+    fn set_and_add_immediate() {
+        let cpu = run(&[0x6AFF, 0x7A02]);
+        assert_eq!(v(&cpu, 0xA), 0x01);
+        assert_eq!(v(&cpu, 0xF), 0, "7XNN never sets VF");
+    }
+
+    #[test]
+    fn logic_operations() {
+        let cpu = run(&[0x600C, 0x610A, 0x8010]);
+        assert_eq!(v(&cpu, 0), 0x0A);
+        let cpu = run(&[0x600C, 0x610A, 0x8011]);
+        assert_eq!(v(&cpu, 0), 0x0E);
+        let cpu = run(&[0x600C, 0x610A, 0x8012]);
+        assert_eq!(v(&cpu, 0), 0x08);
+        let cpu = run(&[0x600C, 0x610A, 0x8013]);
+        assert_eq!(v(&cpu, 0), 0x06);
+    }
+
+    #[test]
+    fn logic_resets_vf_quirk() {
+        let program = [0x6F05, 0x600C, 0x610A, 0x8011];
+        let mut cpu = new_cpu_with_profile(&program, Profile::CosmacVip);
+        for _ in 0..program.len() { cpu.step().unwrap(); }
+        assert_eq!(v(&cpu, 0xF), 0);
+
+        let cpu = run(&program);
+        assert_eq!(v(&cpu, 0xF), 5);
+    }
+
+    #[test]
+    fn add_sets_carry() {
+        let cpu = run(&[0x60F0, 0x6120, 0x8014]);
+        assert_eq!(v(&cpu, 0), 0x10);
+        assert_eq!(v(&cpu, 0xF), 1);
+
+        let cpu = run(&[0x6010, 0x6120, 0x8014]);
+        assert_eq!(v(&cpu, 0), 0x30);
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn add_into_vf_keeps_carry() {
+        let cpu = run(&[0x6FF0, 0x6120, 0x8F14]);
+        assert_eq!(v(&cpu, 0xF), 1);
+        let cpu = run(&[0x6F10, 0x6120, 0x8F14]);
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn subtract_sets_no_borrow() {
+        let cpu = run(&[0x6030, 0x6110, 0x8015]);
+        assert_eq!(v(&cpu, 0), 0x20);
+        assert_eq!(v(&cpu, 0xF), 1);
+
+        let cpu = run(&[0x6010, 0x6130, 0x8015]);
+        assert_eq!(v(&cpu, 0), 0xE0);
+        assert_eq!(v(&cpu, 0xF), 0);
+
+        let cpu = run(&[0x6010, 0x6110, 0x8015]);
+        assert_eq!(v(&cpu, 0), 0);
+        assert_eq!(v(&cpu, 0xF), 1, "equal operands do not borrow");
+    }
+
+    #[test]
+    fn subtract_into_vf_keeps_flag() {
+        let cpu = run(&[0x6F30, 0x6110, 0x8F15]);
+        assert_eq!(v(&cpu, 0xF), 1);
+        let cpu = run(&[0x6F10, 0x6130, 0x8F15]);
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn reverse_subtract_sets_no_borrow() {
+        let cpu = run(&[0x6010, 0x6130, 0x8017]);
+        assert_eq!(v(&cpu, 0), 0x20);
+        assert_eq!(v(&cpu, 0xF), 1);
+
+        let cpu = run(&[0x6030, 0x6110, 0x8017]);
+        assert_eq!(v(&cpu, 0), 0xE0);
+        assert_eq!(v(&cpu, 0xF), 0);
+
+        let cpu = run(&[0x6010, 0x6110, 0x8017]);
+        assert_eq!(v(&cpu, 0xF), 1, "equal operands do not borrow");
+    }
+
+    #[test]
+    fn reverse_subtract_into_vf_keeps_flag() {
+        let cpu = run(&[0x6F10, 0x6130, 0x8F17]);
+        assert_eq!(v(&cpu, 0xF), 1);
+        let cpu = run(&[0x6F30, 0x6110, 0x8F17]);
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn shift_right() {
+        let cpu = run(&[0x6005, 0x6180, 0x8016]);
+        assert_eq!(v(&cpu, 0), 0x02);
+        assert_eq!(v(&cpu, 0xF), 1);
+
+        let mut cpu = new_cpu_with_profile(&[0x6005, 0x6180, 0x8016], Profile::CosmacVip);
+        for _ in 0..3 { cpu.step().unwrap(); }
+        assert_eq!(v(&cpu, 0), 0x40, "VIP shifts VY into VX");
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn shift_left() {
+        let cpu = run(&[0x6081, 0x6101, 0x801E]);
+        assert_eq!(v(&cpu, 0), 0x02);
+        assert_eq!(v(&cpu, 0xF), 1);
+
+        let mut cpu = new_cpu_with_profile(&[0x6081, 0x6101, 0x801E], Profile::CosmacVip);
+        for _ in 0..3 { cpu.step().unwrap(); }
+        assert_eq!(v(&cpu, 0), 0x02, "VIP shifts VY into VX");
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn shift_into_vf_keeps_flag() {
+        let cpu = run(&[0x6F03, 0x8FF6]);
+        assert_eq!(v(&cpu, 0xF), 1);
+        let cpu = run(&[0x6F40, 0x8FFE]);
+        assert_eq!(v(&cpu, 0xF), 0);
+    }
+
+    #[test]
+    fn set_index() {
+        let cpu = run(&[0xA123]);
+        assert_eq!(cpu.registers().index, 0x123);
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        let mut cpu = new_cpu_with_profile(&[0x6004, 0x6302, 0xB310], Profile::CosmacVip);
+        for _ in 0..3 { cpu.step().unwrap(); }
+        assert_eq!(cpu.registers().address, 0x314);
+
+        let cpu = run(&[0x6004, 0x6302, 0xB310]);
+        assert_eq!(cpu.registers().address, 0x312, "SUPER-CHIP adds VX");
+    }
+
+    #[test]
+    fn random_is_masked() {
+        for _ in 0..32 {
+            let cpu = run(&[0xC00F]);
+            assert_eq!(v(&cpu, 0) & 0xF0, 0);
+        }
+    }
+
+    #[test]
+    fn draw_sprite_and_collide() {
+        let mut cpu = new_cpu(&[0xA000, 0x6002, 0x6103, 0xD015, 0xD015]);
+        for _ in 0..4 { cpu.step().unwrap(); }
+        {
+            let screen = cpu.display().screen();
+            assert_eq!(&screen[3 * 64 + 2..3 * 64 + 6], &[1, 1, 1, 1]);
+            assert_eq!(&screen[4 * 64 + 2..4 * 64 + 6], &[1, 0, 0, 1]);
+        }
+        assert_eq!(v(&cpu, 0xF), 0);
+
+        cpu.step().unwrap();
+        assert!(cpu.display().screen().iter().all(|&p| p == 0));
+        assert_eq!(v(&cpu, 0xF), 1);
+    }
+
+    #[test]
+    fn draw_sprite_clips_or_wraps() {
+        let program = [0xA000, 0x603E, 0x6100, 0xD011];
+        let cpu = run(&program);
+        assert_eq!(cpu.display().screen()[0], 0, "SUPER-CHIP clips at the edge");
+
+        let cpu = run(&[0xA000, 0x6000, 0x611E, 0xD015]);
+        assert_eq!(v(&cpu, 0xF), 0, "clipped rows only count in high resolution");
+        let cpu = run(&[0x00FF, 0xA000, 0x6000, 0x613E, 0xD015]);
+        assert_eq!(v(&cpu, 0xF), 3, "SUPER-CHIP counts rows clipped off the bottom");
+
+        let mut cpu = new_cpu_with_profile(&program, Profile::XoChip);
+        for _ in 0..program.len() { cpu.step().unwrap(); }
+        assert_eq!(&cpu.display().screen()[62..64], &[1, 1]);
+        assert_eq!(&cpu.display().screen()[0..2], &[1, 1], "XO-CHIP wraps");
+    }
+
+    #[test]
+    fn draw_sprite_wraps_start_position() {
+        let cpu = run(&[0xA000, 0x6041, 0x6121, 0xD011]);
+        assert_eq!(&cpu.display().screen()[64 + 1..64 + 5], &[1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn skip_on_key() {
+        let mut cpu = new_cpu(&[0x6007, 0xE09E]);
+        cpu.keypad_mut().press(7);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x206);
+
+        let cpu = run(&[0x6007, 0xE09E]);
+        assert_eq!(cpu.registers().address, 0x204);
+    }
+
+    #[test]
+    fn skip_on_not_key() {
+        let cpu = run(&[0x6007, 0xE0A1]);
+        assert_eq!(cpu.registers().address, 0x206);
+
+        let mut cpu = new_cpu(&[0x6007, 0xE0A1]);
+        cpu.keypad_mut().press(7);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x204);
+    }
+
+    #[test]
+    fn wait_for_key() {
+        let mut cpu = new_cpu(&[0xF30A, 0x6001]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.waiting_for_key(), Some(3));
+        cpu.step().unwrap();
+        assert_eq!(cpu.waiting_for_key(), Some(3));
+        cpu.keypad_mut().press(0xB);
+        cpu.step().unwrap();
+        assert_eq!(cpu.waiting_for_key(), Some(3), "waits for the release");
+        cpu.keypad_mut().release(0xB);
+        cpu.step().unwrap();
+        assert_eq!(cpu.waiting_for_key(), None);
+        assert_eq!(v(&cpu, 3), 0xB);
+    }
+
+    #[test]
+    fn wait_for_key_ignores_held_keys() {
+        let mut cpu = new_cpu(&[0xF30A]);
+        cpu.keypad_mut().press(1);
+        cpu.step().unwrap();
+        cpu.keypad_mut().release(1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.waiting_for_key(), Some(3), "1 was held before the wait");
+
+        cpu.keypad_mut().press(2);
+        cpu.keypad_mut().release(2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.waiting_for_key(), None);
+        assert_eq!(v(&cpu, 3), 2);
+    }
+
+    #[test]
+    fn renders_only_changed_frames() {
+        // Draws, then clears twice and loops.
+        let mut cpu = new_cpu(&[0xD015, 0x00E0, 0x00E0, 0x1206]);
+        cpu.set_clock_speed(60);
+        for _ in 0..6 {
+            cpu.run_frame().unwrap();
+        }
+        assert_eq!(cpu.system().renders, 2, "the draw and the first clear");
+        assert_eq!(cpu.frames(), 6);
+
+        cpu.refresh();
+        assert_eq!(cpu.system().renders, 3);
+        assert_eq!(cpu.system().frames_ended, 7, "refreshing lets the host poll its events");
+    }
+
+    #[test]
+    fn timers() {
+        let mut cpu = new_cpu(&[0x6005, 0xF015, 0xF018, 0xF207]);
+        for _ in 0..3 { cpu.step().unwrap(); }
+        assert_eq!(cpu.registers().delay_timer, 5);
+        assert_eq!(cpu.registers().sound_timer, 5);
+        cpu.tick_timers();
+        cpu.step().unwrap();
+        assert_eq!(v(&cpu, 2), 4);
+    }
+
+    #[test]
+    fn add_to_index() {
+        let cpu = run(&[0xA100, 0x6020, 0xF01E]);
+        assert_eq!(cpu.registers().index, 0x120);
+    }
+
+    #[test]
+    fn font_characters() {
+        let cpu = run(&[0x600A, 0xF029]);
+        assert_eq!(cpu.registers().index, FONT_ADDRESS + 50);
+        let cpu = run(&[0x6003, 0xF030]);
+        assert_eq!(cpu.registers().index, BIG_FONT_ADDRESS + 30);
+    }
+
+    #[test]
+    fn store_bcd() {
+        let cpu = run(&[0xA300, 0x60FE, 0xF033]);
+        assert_eq!(cpu.memory().read(0x300), 2);
+        assert_eq!(cpu.memory().read(0x301), 5);
+        assert_eq!(cpu.memory().read(0x302), 4);
+
+        let cpu = run(&[0xA300, 0x6007, 0xF033]);
+        assert_eq!(&cpu.memory().bytes()[0x300..0x303], &[0, 0, 7]);
+    }
+
+    #[test]
+    fn store_registers_includes_vx() {
+        let cpu = run(&[0xA300, 0x6011, 0x6122, 0x6233, 0x6344, 0xF255]);
+        assert_eq!(&cpu.memory().bytes()[0x300..0x304], &[0x11, 0x22, 0x33, 0]);
+        assert_eq!(cpu.registers().index, 0x300);
+    }
+
+    #[test]
+    fn load_registers_includes_vx() {
+        let cpu = run(&[0xA000, 0xF265]);
+        assert_eq!(v(&cpu, 0), 0xF0);
+        assert_eq!(v(&cpu, 1), 0x90);
+        assert_eq!(v(&cpu, 2), 0x90);
+        assert_eq!(v(&cpu, 3), 0);
+        assert_eq!(cpu.registers().index, 0);
+    }
+
+    #[test]
+    fn store_and_load_all_registers() {
+        let cpu = run(&[0xA300, 0x6F99, 0xFF55, 0x6F00, 0xFF65]);
+        assert_eq!(cpu.memory().read(0x30F), 0x99);
+        assert_eq!(v(&cpu, 0xF), 0x99);
+    }
+
+    #[test]
+    fn load_store_increments_index_quirk() {
+        let program = [0xA300, 0xF255, 0xF165];
+        let mut cpu = new_cpu_with_profile(&program, Profile::CosmacVip);
+        for _ in 0..program.len() { cpu.step().unwrap(); }
+        assert_eq!(cpu.registers().index, 0x305);
+
+        let mut cpu = new_cpu_with_profile(&program, Profile::Chip48);
+        for _ in 0..program.len() { cpu.step().unwrap(); }
+        assert_eq!(cpu.registers().index, 0x303, "CHIP-48 advances I by X");
+    }
+
+    #[test]
+    fn high_resolution_and_big_sprites() {
+        let mut cpu = new_cpu(&[0x00FF, 0xA300, 0x6000, 0x6100, 0xD010]);
+        for _ in 0..5 { cpu.step().unwrap(); }
+        assert_eq!(cpu.display().width(), 128);
+        assert_eq!(cpu.display().height(), 64);
+        assert_eq!(v(&cpu, 0xF), 0);
+
+        let cpu = run(&[0x00FF, 0x00FE]);
+        assert_eq!(cpu.display().width(), 64);
+    }
+
+    #[test]
+    fn big_sprite_counts_collided_rows() {
+        let mut program = vec![0x00FF, 0xA20E, 0x6000, 0x6100, 0xD010, 0xD010, 0x1000];
+        program.extend_from_slice(&[0xFFFF, 0xFFFF, 0xFFFF]);
+        let mut cpu = new_cpu(&program);
+        for _ in 0..6 { cpu.step().unwrap(); }
+        assert_eq!(v(&cpu, 0xF), 3);
+    }
+
+    #[test]
+    fn scrolling() {
+        let cpu = run(&[0xA000, 0xD001, 0x00C2]);
+        assert_eq!(&cpu.display().screen()[128..132], &[1, 1, 1, 1]);
+
+        let cpu = run(&[0xA000, 0xD001, 0x00FB]);
+        assert_eq!(&cpu.display().screen()[0..8], &[0, 0, 0, 0, 1, 1, 1, 1]);
+
+        let cpu = run(&[0xA000, 0x6004, 0x6100, 0xD011, 0x00FC]);
+        assert_eq!(&cpu.display().screen()[0..5], &[1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn rpl_flags() {
+        let cpu = run(&[0x6011, 0x6122, 0xF175, 0x6000, 0x6100, 0xF185]);
+        assert_eq!(v(&cpu, 0), 0x11);
+        assert_eq!(v(&cpu, 1), 0x22);
+        assert_eq!(&cpu.rpl_flags()[0..3], &[0x11, 0x22, 0]);
+    }
+
+    #[test]
+    fn exit() {
+        let mut cpu = new_cpu(&[0x00FD, 0x6001]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(v(&cpu, 0), 0);
+    }
+
+    #[test]
+    fn carries_cycle_remainder() {
+        let mut cpu = new_cpu(&[0x1200]);
+        cpu.set_clock_speed(1000);
+        let budgets: Vec<u32> = (0..3).map(|_| cpu.frame_budget()).collect();
+        assert_eq!(budgets, vec![16, 17, 17]);
+        let total: u32 = (0..57).map(|_| cpu.frame_budget()).sum();
+        assert_eq!(total + 50, 1000, "60 frames run exactly one second of instructions");
+    }
+
+    #[test]
+    fn halted_on_jump_to_self() {
+        let mut cpu = new_cpu(&[0x6001, 0x1202]);
+        assert!(!cpu.is_halted());
+        cpu.step().unwrap();
+        assert!(cpu.is_halted());
+    }
+
+    #[test]
+    fn halted_only_on_jumps_to_the_full_address() {
+        // 0x1200 at 0x1200 jumps back to 0x200.
+        let mut program = vec![0x7001; 0x800];
+        program.push(0x1200);
+        let mut cpu = new_cpu_with_profile(&program, Profile::XoChip);
+        for _ in 0..0x800 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.registers().address, 0x1200);
+        assert!(!cpu.is_halted());
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x200);
+
+        let cpu = new_cpu_with_profile(&[0xF000, 0x0200], Profile::XoChip);
+        assert!(!cpu.is_halted(), "F000 NNNN loads I");
+    }
+
+    #[test]
+    fn calls_leave_memory_alone() {
+        // An XO-CHIP program big enough to reach where the stack used to be.
+        let mut program = vec![0xABCD; 0x1000];
+        program[..3].copy_from_slice(&[0x2204, 0x1202, 0x00EE]);
+        let mut cpu = new_cpu_with_profile(&program, Profile::XoChip);
+        let before = cpu.memory().bytes().to_vec();
+        cpu.step().unwrap();
+        assert_eq!(cpu.stack(), vec![0x202]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x202);
+        assert!(cpu.stack().is_empty());
+        assert_eq!(cpu.memory().bytes(), &before[..]);
+    }
+
+    #[test]
+    fn deep_calls_keep_the_innermost_returns() {
+        // Each call calls the next, 17 deep.
+        let program: Vec<u16> = (0..17).map(|n| 0x2202 + n * 2).collect();
+        let mut cpu = new_cpu(&program);
+        for _ in 0..17 {
+            cpu.step().unwrap();
+        }
+        let stack = cpu.stack();
+        assert_eq!(stack.len(), STACK_SIZE);
+        assert_eq!((stack[0], stack[15]), (0x204, 0x222));
+    }
+
+    #[test]
+    fn xo_chip_long_index() {
+        let mut cpu = new_cpu_with_profile(&[0xF000, 0x1234, 0x6001], Profile::XoChip);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().index, 0x1234);
+        assert_eq!(cpu.registers().address, 0x204);
+    }
+
+    #[test]
+    fn xo_chip_skips_long_index() {
+        let mut cpu = new_cpu_with_profile(&[0x3000, 0xF000, 0x1234, 0x6001], Profile::XoChip);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().address, 0x206);
+    }
+
+    #[test]
+    fn xo_chip_register_ranges() {
+        let program = [0xA300, 0x6111, 0x6222, 0x6333, 0x5132, 0x5312, 0x6100, 0xA300, 0x5113];
+        let mut cpu = new_cpu_with_profile(&program, Profile::XoChip);
+        for _ in 0..6 { cpu.step().unwrap(); }
+        assert_eq!(&cpu.memory().bytes()[0x300..0x303], &[0x33, 0x22, 0x11]);
+        assert_eq!(cpu.registers().index, 0x300);
+
+        for _ in 0..3 { cpu.step().unwrap(); }
+        assert_eq!(v(&cpu, 1), 0x33);
+    }
+
+    #[test]
+    fn xo_chip_memory() {
+        let mut cpu = new_cpu_with_profile(&[0xF000, 0xFF00, 0x6042, 0xF055], Profile::XoChip);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory().size(), 0x10000);
+        assert_eq!(cpu.memory().read(0xFF00), 0x42);
+    }
+
+    #[test]
+    fn xo_chip_planes() {
+        let mut program = vec![0xF301, 0xA20A, 0x6000, 0x6100, 0xD011];
+        program.push(0x80FF);
+        let mut cpu = new_cpu_with_profile(&program, Profile::XoChip);
+        for _ in 0..5 { cpu.step().unwrap(); }
+        assert_eq!(&cpu.display().screen()[0..3], &[3, 2, 2]);
+        assert_eq!(cpu.display().screen()[8], 0);
+    }
+
+    #[test]
+    fn xo_chip_instructions_need_xo_chip_profile() {
+        let mut cpu = new_cpu(&[0xF000, 0x1234]);
+        assert_eq!(cpu.step(), Err(CpuError::UnknownOpcode { address: 0x200, opcode: 0xF000 }));
+        assert_eq!(cpu.registers().index, 0);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut cpu = new_cpu(&[0x8008, 0x6001]);
+        assert_eq!(cpu.step(), Err(CpuError::UnknownOpcode { address: 0x200, opcode: 0x8008 }));
+        cpu.step().unwrap();
+        assert_eq!(v(&cpu, 0), 1);
+    }
+
+    #[test]
+    fn machine_code_call() {
+        let mut cpu = new_cpu(&[0x0123]);
+        assert_eq!(cpu.step(), Err(CpuError::MachineCodeCall { address: 0x200, opcode: 0x123 }));
+    }
+
+    #[test]
+    fn lenient_mode_reports_and_continues() {
+        let mut cpu = new_cpu(&[0xFFFF, 0x6001, 0x1204]);
+        assert_eq!(cpu.run_frame(), Ok(false));
+        assert_eq!(v(&cpu, 0), 1);
+        assert_eq!(cpu.system().errors, vec![CpuError::UnknownOpcode { address: 0x200, opcode: 0xFFFF }]);
+    }
+
+    #[test]
+    fn strict_mode_stops() {
+        let mut cpu = new_cpu(&[0xFFFF, 0x6001, 0x1204]);
+        cpu.set_strict(true);
+        assert_eq!(cpu.run_frame(), Err(CpuError::UnknownOpcode { address: 0x200, opcode: 0xFFFF }));
+        assert_eq!(v(&cpu, 0), 0);
+        assert!(cpu.system().errors.is_empty());
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let program = [0x00FF, 0x6005, 0xA300, 0xF033, 0xD015, 0x2210, 0x1210, 0x1210, 0x7001, 0x1210];
+        let mut cpu = new_cpu(&program);
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        let mut saved = Vec::new();
+        cpu.save_state(&mut saved).unwrap();
+
+        let mut other = new_cpu(&program);
+        other.load_state(&mut &saved[..]).unwrap();
+        assert_eq!(other.registers().address, 0x210);
+        assert_eq!(other.registers().index, 0x300);
+        assert_eq!(other.stack(), vec![0x20C]);
+        assert_eq!(other.cycles(), 6);
+        assert!(other.display().is_hires());
+        assert_eq!(other.display().screen(), cpu.display().screen());
+        assert_eq!(other.memory().bytes(), cpu.memory().bytes());
+
+        let mut resaved = Vec::new();
+        other.save_state(&mut resaved).unwrap();
+        assert_eq!(resaved, saved);
+    }
+
+    #[test]
+    fn loads_version_1_states() {
+        let mut cpu = new_cpu(&[0x2204, 0x0000, 0xF30A]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        let mut saved = Vec::new();
+        cpu.save_state(&mut saved).unwrap();
+
+        // Version 1 had no byte for the key FX0A is waiting to be released,
+        // which follows the waiting register 49 bytes in, and nothing of the
+        // random number generator or its name, the stack or the CHIP-48
+        // index quirk at the end. Its stack was in memory instead, with the
+        // stack pointer six bytes before the waiting register.
+        saved[4] = 1;
+        assert_eq!(&saved[43..45], &[1, 0]);
+        saved[43..45].copy_from_slice(&[0xA2, 0x0E]);
+        assert_eq!(saved.remove(50), 0xFF);
+        let len = saved.len();
+        saved.truncate(len - (8 + 1 + 8 + 1 + 16) - STACK_SIZE * 2 - 1);
+        let stack = saved.len() - MEMORY_SIZE + OLD_STACK_ADDRESS as usize;
+        saved[stack..stack + 2].copy_from_slice(&[0x02, 0x02]);
+        let mut other = new_cpu(&[0x2204, 0x0000, 0xF30A]);
+        other.load_state(&mut &saved[..]).unwrap();
+        assert_eq!(other.waiting_for_key(), Some(3));
+        assert_eq!(other.stack(), vec![0x202]);
+    }
+
+    #[test]
+    fn load_state_refuses_mismatches() {
+        let mut saved = Vec::new();
+        new_cpu(&[0x6001]).save_state(&mut saved).unwrap();
+
+        let mut other_rom = new_cpu(&[0x6002]);
+        match other_rom.load_state(&mut &saved[..]) {
+            Err(StateError::RomMismatch) => {},
+            other => panic!("{:?}", other),
+        }
+
+        let mut other_profile = new_cpu_with_profile(&[0x6001], Profile::CosmacVip);
+        match other_profile.load_state(&mut &saved[..]) {
+            Err(StateError::ProfileMismatch(ref name)) if name == "schip" => {},
+            other => panic!("{:?}", other),
+        }
+
+        let mut cpu = new_cpu(&[0x6001]);
+        cpu.step().unwrap();
+        match cpu.load_state(&mut &saved[..saved.len() - 1]) {
+            Err(StateError::Corrupt) => {},
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(v(&cpu, 0), 1);
+        match cpu.load_state(&mut &b"not a state"[..]) {
+            Err(StateError::NotAState) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn seeded_random() {
+        let program = [0xC0FF, 0xC1FF, 0xC2FF, 0xC3FF];
+        let mut a = new_cpu(&program);
+        let mut b = new_cpu(&program);
+        a.set_seed(1234);
+        b.set_seed(1234);
+        for _ in 0..4 {
+            a.step().unwrap();
+            b.step().unwrap();
+        }
+        assert_eq!(a.registers().data, b.registers().data);
+        assert_eq!(a.seed(), 1234);
+    }
+
+    #[test]
+    fn random_numbers_survive_save_states() {
+        let program = [0xC0FF, 0xC1FF, 0xC2FF, 0xC3FF];
+        for &mode in [RandomMode::XorShift, RandomMode::Vip].iter() {
+            let mut cpu = new_cpu(&program);
+            cpu.set_random_source(mode.source(0));
+            cpu.set_seed(99);
+            cpu.step().unwrap();
+            let mut saved = Vec::new();
+            cpu.save_state(&mut saved).unwrap();
+            for _ in 0..3 { cpu.step().unwrap(); }
+
+            let mut other = new_cpu(&program);
+            other.set_random_source(mode.source(0));
+            other.load_state(&mut &saved[..]).unwrap();
+            assert_eq!(other.seed(), 99);
+            for _ in 0..3 { other.step().unwrap(); }
+            assert_eq!(other.registers().data, cpu.registers().data, "{}", mode.name());
+        }
+    }
+
+    #[test]
+    fn load_state_refuses_other_random_modes() {
+        let mut cpu = new_cpu(&[0xC0FF]);
+        cpu.set_random_source(RandomMode::Vip.source(0));
+        let mut saved = Vec::new();
+        cpu.save_state(&mut saved).unwrap();
+
+        match new_cpu(&[0xC0FF]).load_state(&mut &saved[..]) {
+            Err(StateError::RandomMismatch(ref name)) if name == "vip" => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn vip_random() {
+        let mut cpu = new_cpu(&[0xC0FF, 0xC1FF]);
+        cpu.set_seed(0);
+        cpu.set_random_source(Box::new(VipRandom::new(0)));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // The font bytes at 0x001 and 0x002, both 0x90, summed and rotated.
+        assert_eq!(v(&cpu, 0), 0x48);
+        assert_eq!(v(&cpu, 1), 0x6C);
+    }
+}
@@ -0,0 +1,506 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use cpu::Cpu;
+use error::CpuError;
+use instruction::Instruction;
+use memory::WatchHit;
+use traits::Chip8System;
+
+const HELP: &str = "\
+Addresses and values are hex, counts and lengths are decimal.
+  s, step [n]             Execute n instructions (default 1)
+  n, next                 Execute one instruction, running calls to completion
+  c, continue             Run until a breakpoint, watchpoint or error
+  b, break <addr>         Stop when the program counter reaches addr
+  mb, mbreak <addr> <v>   Stop when the byte at addr becomes v
+  w, watch <addr>         Stop when addr is written to
+  d, delete <addr>        Remove the breakpoints and watchpoints on addr
+  i, info                 List breakpoints and watchpoints
+  r, regs                 Print the registers and timers
+  stack                   Print the return addresses on the stack
+  x <addr> [len]          Dump len bytes of memory (default 64)
+  h, help                 Print this help
+  q, quit                 Stop debugging
+An empty line repeats the last command.";
+
+/// Why the debugger handed control back to the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested instructions were executed.
+    Stepped,
+    /// The program counter reached a breakpoint.
+    Breakpoint(u16),
+    /// A watched byte became the value a memory breakpoint waits for.
+    MemoryBreakpoint { address: u16, value: u8 },
+    /// Watched addresses were written to, in the order of the writes.
+    Watchpoint(Vec<WatchHit>),
+    /// An invalid instruction was hit. The debugger stops on these whether
+    /// or not the `Cpu` is in strict mode.
+    Error(CpuError),
+    /// The host has been closed.
+    Closed,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Stepped => Ok(()),
+            StopReason::Breakpoint(addr) => write!(f, "Breakpoint at {:03X}", addr),
+            StopReason::MemoryBreakpoint { address, value } =>
+                write!(f, "Memory breakpoint: {:03X} is now {:02X}", address, value),
+            StopReason::Watchpoint(ref hits) => {
+                for (n, hit) in hits.iter().enumerate() {
+                    if n > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "Watchpoint: {:03X} changed from {:02X} to {:02X}", hit.address, hit.old, hit.new)?;
+                }
+                Ok(())
+            },
+            StopReason::Error(ref err) => write!(f, "{}", err),
+            StopReason::Closed => write!(f, "Closed"),
+        }
+    }
+}
+
+/// What the caller should do once a command has been executed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Read the next command.
+    Prompt,
+    /// Call `run_frame` once per frame until it stops, then report why with
+    /// `report_stop`.
+    Continue,
+    /// Stop debugging.
+    Quit,
+}
+
+struct MemoryBreakpoint {
+    address: u16,
+    value: u8,
+    matched: bool,
+}
+
+/// An interactive debugger wrapped around a `Cpu`.
+///
+/// Commands are read by the caller and passed to `execute` one line at a
+/// time. Running is done a frame at a time by `run_frame`, so the caller can
+/// keep pacing frames and presenting the screen while the program runs.
+pub struct Debugger<T: Chip8System> {
+    cpu: Cpu<T>,
+    breakpoints: Vec<u16>,
+    memory_breakpoints: Vec<MemoryBreakpoint>,
+    frame_remaining: u32,
+    step_over: Option<(u16, u16)>,
+    last_command: String,
+}
+
+impl<T: Chip8System> Debugger<T> {
+    pub fn new(mut cpu: Cpu<T>) -> Debugger<T> {
+        let budget = cpu.frame_budget();
+        Debugger {
+            cpu,
+            breakpoints: Vec::new(),
+            memory_breakpoints: Vec::new(),
+            frame_remaining: budget,
+            step_over: None,
+            last_command: String::new(),
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu<T> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<T> {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(self) -> Cpu<T> {
+        self.cpu
+    }
+
+    /// Stops when the program counter reaches `addr`. Like every address
+    /// given to the debugger, it wraps around to the size of memory.
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        let addr = self.cpu.memory().wrap(addr);
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    /// Stops when the byte at `addr` changes to `value`.
+    pub fn add_memory_breakpoint(&mut self, addr: u16, value: u8) {
+        let addr = self.cpu.memory().wrap(addr);
+        let matched = self.cpu.memory().read(addr) == value;
+        self.memory_breakpoints.push(MemoryBreakpoint { address: addr, value, matched });
+    }
+
+    /// Stops when `addr` is written to.
+    pub fn add_watchpoint(&mut self, addr: u16) {
+        self.cpu.memory_mut().add_watchpoint(addr);
+    }
+
+    /// Removes every breakpoint and watchpoint on `addr`, returning false if
+    /// there were none.
+    pub fn delete(&mut self, addr: u16) -> bool {
+        let addr = self.cpu.memory().wrap(addr);
+        let before = self.breakpoints.len() + self.memory_breakpoints.len();
+        self.breakpoints.retain(|&bp| bp != addr);
+        self.memory_breakpoints.retain(|bp| bp.address != addr);
+        let removed = self.breakpoints.len() + self.memory_breakpoints.len() != before;
+        self.cpu.memory_mut().remove_watchpoint(addr) || removed
+    }
+
+    /// Executes a single instruction, returning why execution should stop
+    /// there, if it should. Any `next` in progress ends with the stop.
+    fn step_one(&mut self) -> Option<StopReason> {
+        let reason = self.execute_one();
+        if reason.is_some() {
+            self.step_over = None;
+        }
+        reason
+    }
+
+    fn execute_one(&mut self) -> Option<StopReason> {
+        while self.frame_remaining == 0 {
+            if let Some(reason) = self.next_frame() {
+                return Some(reason);
+            }
+        }
+        self.frame_remaining -= 1;
+
+        if let Err(err) = self.cpu.step() {
+            return Some(StopReason::Error(err));
+        }
+
+        let hits = self.cpu.memory_mut().take_watch_hits();
+        if !hits.is_empty() {
+            return Some(StopReason::Watchpoint(hits));
+        }
+
+        let mut reason = None;
+        for bp in self.memory_breakpoints.iter_mut() {
+            let matched = self.cpu.memory().read(bp.address) == bp.value;
+            if matched && !bp.matched && reason.is_none() {
+                reason = Some(StopReason::MemoryBreakpoint { address: bp.address, value: bp.value });
+            }
+            bp.matched = matched;
+        }
+        if reason.is_some() {
+            return reason;
+        }
+
+        let regs = self.cpu.registers();
+        if self.step_over == Some((regs.address, regs.stack)) {
+            return Some(StopReason::Stepped);
+        }
+        let pc = self.cpu.memory().wrap(regs.address);
+        if self.breakpoints.contains(&pc) {
+            return Some(StopReason::Breakpoint(pc));
+        }
+        None
+    }
+
+    fn next_frame(&mut self) -> Option<StopReason> {
+        if self.cpu.end_frame() {
+            return Some(StopReason::Closed);
+        }
+        self.frame_remaining = self.cpu.frame_budget();
+        None
+    }
+
+    /// Executes `count` instructions, stopping early at a breakpoint,
+    /// watchpoint or error.
+    pub fn step(&mut self, count: u32) -> StopReason {
+        for _ in 0..count {
+            if let Some(reason) = self.step_one() {
+                return reason;
+            }
+        }
+        StopReason::Stepped
+    }
+
+    /// Runs the rest of the current frame, ending it, unless something stops
+    /// execution first.
+    pub fn run_frame(&mut self) -> Option<StopReason> {
+        while self.frame_remaining > 0 {
+            if let Some(reason) = self.step_one() {
+                return Some(reason);
+            }
+        }
+        self.next_frame()
+    }
+
+    /// Executes one command line, writing its output to `out`.
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<Action> {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            line.trim().to_string()
+        };
+        self.last_command = line.clone();
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.first() {
+            Some(command) => *command,
+            None => return Ok(Action::Prompt),
+        };
+        let args = &words[1..];
+
+        match (command, args.len()) {
+            ("s", _) | ("step", _) => {
+                let count = match args.first() {
+                    Some(count) => match count.parse() {
+                        Ok(count) => count,
+                        Err(_) => return usage(out, "step [n]"),
+                    },
+                    None => 1,
+                };
+                let reason = self.step(count);
+                self.report_stop(&reason, out)?;
+                if reason == StopReason::Closed {
+                    return Ok(Action::Quit);
+                }
+            },
+            ("n", 0) | ("next", 0) => {
+                let regs = self.cpu.registers();
+                if let Instruction::Call(_) = Instruction::decode(self.cpu.memory().read_word(regs.address), 0, false) {
+                    self.step_over = Some((regs.address.wrapping_add(2), regs.stack));
+                    return Ok(Action::Continue);
+                }
+                let reason = self.step(1);
+                self.report_stop(&reason, out)?;
+            },
+            ("c", 0) | ("continue", 0) => return Ok(Action::Continue),
+            ("b", 1) | ("break", 1) => match self.parse_address(args[0]) {
+                Some(addr) => {
+                    self.add_breakpoint(addr);
+                    writeln!(out, "Breakpoint at {:03X}", addr)?;
+                },
+                None => return usage(out, "break <addr>"),
+            },
+            ("mb", 2) | ("mbreak", 2) => match (self.parse_address(args[0]), parse_hex(args[1])) {
+                (Some(addr), Some(value)) if value <= 0xFF => {
+                    self.add_memory_breakpoint(addr, value as u8);
+                    writeln!(out, "Memory breakpoint when {:03X} is {:02X}", addr, value)?;
+                },
+                _ => return usage(out, "mbreak <addr> <value>"),
+            },
+            ("w", 1) | ("watch", 1) => match self.parse_address(args[0]) {
+                Some(addr) => {
+                    self.add_watchpoint(addr);
+                    writeln!(out, "Watchpoint on {:03X}", addr)?;
+                },
+                None => return usage(out, "watch <addr>"),
+            },
+            ("d", 1) | ("delete", 1) => match self.parse_address(args[0]) {
+                Some(addr) => if !self.delete(addr) {
+                    writeln!(out, "Nothing set on {:03X}", addr)?;
+                },
+                None => return usage(out, "delete <addr>"),
+            },
+            ("i", 0) | ("info", 0) => self.print_breakpoints(out)?,
+            ("r", 0) | ("regs", 0) => writeln!(out, "{}", self.cpu.registers())?,
+            ("stack", 0) => self.print_stack(out)?,
+            ("x", 1) | ("x", 2) => {
+                let len = if args.len() == 2 { args[1].parse().ok() } else { Some(64) };
+                match (parse_hex(args[0]), len) {
+                    (Some(addr), Some(len)) => self.dump_memory(addr, len, out)?,
+                    _ => return usage(out, "x <addr> [len]"),
+                }
+            },
+            ("h", 0) | ("help", 0) => writeln!(out, "{}", HELP)?,
+            ("q", 0) | ("quit", 0) => return Ok(Action::Quit),
+            _ => writeln!(out, "Unknown command '{}', try help", line)?,
+        }
+
+        Ok(Action::Prompt)
+    }
+
+    /// Parses a hex address and wraps it around to the size of memory.
+    fn parse_address(&self, text: &str) -> Option<u16> {
+        parse_hex(text).map(|addr| self.cpu.memory().wrap(addr))
+    }
+
+    /// Reports why execution stopped, followed by the next instruction.
+    pub fn report_stop<W: Write>(&self, reason: &StopReason, out: &mut W) -> io::Result<()> {
+        if *reason != StopReason::Stepped {
+            writeln!(out, "{}", reason)?;
+        }
+        self.print_location(out)
+    }
+
+    /// Prints the address, opcode and mnemonic of the next instruction.
+    pub fn print_location<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mem = self.cpu.memory();
+        let pc = self.cpu.registers().address;
+        let opcode = mem.read_word(pc);
+        let instruction = Instruction::decode(opcode, mem.read_word(pc.wrapping_add(2)), self.cpu.is_xo_chip());
+        writeln!(out, "{:03X}: {:04X}  {}", pc, opcode, instruction)
+    }
+
+    fn print_breakpoints<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for addr in self.breakpoints.iter() {
+            writeln!(out, "Breakpoint at {:03X}", addr)?;
+        }
+        for bp in self.memory_breakpoints.iter() {
+            writeln!(out, "Memory breakpoint when {:03X} is {:02X}", bp.address, bp.value)?;
+        }
+        for addr in self.cpu.memory().watchpoints() {
+            writeln!(out, "Watchpoint on {:03X}", addr)?;
+        }
+        Ok(())
+    }
+
+    fn print_stack<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let stack = self.cpu.stack();
+        if stack.is_empty() {
+            return writeln!(out, "Stack is empty");
+        }
+        for (depth, addr) in stack.iter().enumerate().rev() {
+            writeln!(out, "#{} {:03X}", depth, addr)?;
+        }
+        Ok(())
+    }
+
+    fn dump_memory<W: Write>(&self, addr: u16, len: u16, out: &mut W) -> io::Result<()> {
+        let mem = self.cpu.memory();
+        for row in (0..len).step_by(16) {
+            let start = addr.wrapping_add(row);
+            let bytes: Vec<String> = (0..16u16.min(len - row))
+                .map(|n| format!("{:02X}", mem.read(start.wrapping_add(n))))
+                .collect();
+            writeln!(out, "{:03X}: {}", start, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+fn usage<W: Write>(out: &mut W, usage: &str) -> io::Result<Action> {
+    writeln!(out, "Usage: {}", usage)?;
+    Ok(Action::Prompt)
+}
+
+/// Parses a hex number, with or without a `0x` prefix.
+fn parse_hex(text: &str) -> Option<u16> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headless::HeadlessSystem;
+
+    fn debugger(program: &[u16]) -> Debugger<HeadlessSystem> {
+        let rom = program.iter().flat_map(|word| vec![(word >> 8) as u8, *word as u8]).collect();
+        Debugger::new(Cpu::new(rom, HeadlessSystem::new()))
+    }
+
+    fn execute(debugger: &mut Debugger<HeadlessSystem>, line: &str) -> (Action, String) {
+        let mut out = Vec::new();
+        let action = debugger.execute(line, &mut out).unwrap();
+        (action, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn step() {
+        let mut dbg = debugger(&[0x6001, 0x6102, 0x6203]);
+        let (_, out) = execute(&mut dbg, "step 2");
+        assert_eq!(out, "204: 6203  LD V2, 0x03\n");
+        assert_eq!(dbg.cpu().registers().data[1], 2);
+        execute(&mut dbg, "");
+        assert_eq!(dbg.cpu().registers().data[2], 3);
+    }
+
+    #[test]
+    fn breakpoint() {
+        let mut dbg = debugger(&[0x7001, 0x1200]);
+        execute(&mut dbg, "break 202");
+        assert_eq!(execute(&mut dbg, "c").0, Action::Continue);
+        assert_eq!(dbg.run_frame(), Some(StopReason::Breakpoint(0x202)));
+        assert_eq!(dbg.run_frame(), Some(StopReason::Breakpoint(0x202)));
+        assert_eq!(dbg.cpu().registers().data[0], 2);
+    }
+
+    #[test]
+    fn next_runs_calls() {
+        let mut dbg = debugger(&[0x2206, 0x6101, 0x1204, 0x6005, 0x00EE]);
+        assert_eq!(execute(&mut dbg, "next").0, Action::Continue);
+        assert_eq!(dbg.run_frame(), Some(StopReason::Stepped));
+        assert_eq!(dbg.cpu().registers().address, 0x202);
+        assert_eq!(dbg.cpu().registers().data[0], 5);
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut dbg = debugger(&[0x6007, 0xA300, 0xF033, 0x1206]);
+        execute(&mut dbg, "watch 302");
+        let hit = WatchHit { address: 0x302, old: 0, new: 7 };
+        assert_eq!(dbg.step(10), StopReason::Watchpoint(vec![hit]));
+    }
+
+    #[test]
+    fn reports_every_watch_hit() {
+        let mut dbg = debugger(&[0x6007, 0xA300, 0xF033, 0x1206]);
+        execute(&mut dbg, "watch 300");
+        execute(&mut dbg, "watch 302");
+        let reason = dbg.step(10);
+        assert_eq!(reason.to_string(), "Watchpoint: 300 changed from 00 to 00\nWatchpoint: 302 changed from 00 to 07");
+    }
+
+    #[test]
+    fn next_ends_at_any_stop() {
+        let mut dbg = debugger(&[0x2206, 0x6101, 0x1204, 0x6005, 0x00EE]);
+        execute(&mut dbg, "break 206");
+        execute(&mut dbg, "next");
+        assert_eq!(dbg.run_frame(), Some(StopReason::Breakpoint(0x206)));
+        execute(&mut dbg, "continue");
+        assert_eq!(dbg.run_frame(), None, "the finished call no longer stops");
+    }
+
+    #[test]
+    fn addresses_wrap_around_memory() {
+        let mut dbg = debugger(&[0x1200]);
+        assert_eq!(execute(&mut dbg, "watch 1300").1, "Watchpoint on 300\n");
+        assert_eq!(execute(&mut dbg, "break 1200").1, "Breakpoint at 200\n");
+        assert_eq!(execute(&mut dbg, "delete 2300").1, "");
+        assert_eq!(execute(&mut dbg, "delete 200").1, "");
+        assert_eq!(execute(&mut dbg, "info").1, "");
+    }
+
+    #[test]
+    fn memory_breakpoint() {
+        let mut dbg = debugger(&[0xA300, 0x7001, 0xF033, 0x1202]);
+        execute(&mut dbg, "mbreak 302 3");
+        let reason = StopReason::MemoryBreakpoint { address: 0x302, value: 3 };
+        assert_eq!(dbg.step(100), reason);
+        assert_eq!(dbg.cpu().registers().data[0], 3);
+    }
+
+    #[test]
+    fn stops_on_errors() {
+        let mut dbg = debugger(&[0xFFFF]);
+        let err = CpuError::UnknownOpcode { address: 0x200, opcode: 0xFFFF };
+        assert_eq!(dbg.step(1), StopReason::Error(err));
+    }
+
+    #[test]
+    fn memory_dump() {
+        let mut dbg = debugger(&[0x0102, 0x0304]);
+        let (_, out) = execute(&mut dbg, "x 0x200 4");
+        assert_eq!(out, "200: 01 02 03 04\n");
+        let (_, out) = execute(&mut dbg, "x 200 17");
+        assert_eq!(out.lines().count(), 2, "lengths are decimal");
+    }
+
+    #[test]
+    fn stack() {
+        let mut dbg = debugger(&[0x2204, 0x0000, 0x2208, 0x0000, 0x1208]);
+        dbg.step(2);
+        let (_, out) = execute(&mut dbg, "stack");
+        assert_eq!(out, "#1 206\n#0 202\n");
+    }
+}
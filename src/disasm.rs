@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use instruction::Instruction;
+use memory::ROM_START;
+
+/// The column instruction comments are aligned to in listings.
+const COMMENT_COLUMN: usize = 28;
+
+/// The most bytes put on one `DB` line.
+const DATA_PER_LINE: usize = 8;
+
+/// A ROM split into code and data by following the program's control flow
+/// from 0x200.
+///
+/// Anything that is never reached by a jump, call, skip or simply running on
+/// from an earlier instruction is assumed to be data. Targets of BNNN are
+/// treated as code, though where the jump really lands depends on V0 at run
+/// time, so jump tables may be partly misidentified as data.
+pub struct Disassembly {
+    rom: Vec<u8>,
+    xo_chip: bool,
+    code: BTreeMap<u16, Instruction>,
+    labels: BTreeMap<u16, String>,
+}
+
+/// What a label marks, in order of precedence when an address is used in
+/// more than one way.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Jump,
+    Call,
+    Start,
+}
+
+impl Disassembly {
+    /// Disassembles `rom`, decoding XO-CHIP instructions if `xo_chip` is set.
+    pub fn new(rom: &[u8], xo_chip: bool) -> Disassembly {
+        let mut disassembly = Disassembly {
+            rom: rom.to_vec(),
+            xo_chip,
+            code: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+        disassembly.trace();
+        disassembly
+    }
+
+    fn end(&self) -> u32 {
+        ROM_START as u32 + self.rom.len() as u32
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        addr >= ROM_START && (addr as u32) < self.end()
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.rom[(addr - ROM_START) as usize]
+    }
+
+    /// Decodes the instruction at `addr`, if a whole one fits in the ROM.
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        if !self.contains(addr) || addr as u32 + 2 > self.end() {
+            return None;
+        }
+        // The word after the instruction may be past the end of memory.
+        let word = |addr: u16| if self.contains(addr) && self.contains(addr.wrapping_add(1)) {
+            ((self.read(addr) as u16) << 8) | self.read(addr + 1) as u16
+        } else {
+            0
+        };
+        let instruction = Instruction::decode(word(addr), word(addr.wrapping_add(2)), self.xo_chip);
+        if addr as u32 + instruction.size() as u32 > self.end() {
+            return None;
+        }
+        Some(instruction)
+    }
+
+    fn trace(&mut self) {
+        let mut kinds = BTreeMap::new();
+        let mut pending = vec![ROM_START];
+        kinds.insert(ROM_START, LabelKind::Start);
+
+        while let Some(addr) = pending.pop() {
+            if self.code.contains_key(&addr) {
+                continue;
+            }
+            let instruction = match self.decode(addr) {
+                Some(Instruction::Unknown(_)) | None => continue,
+                Some(instruction) => instruction,
+            };
+            self.code.insert(addr, instruction);
+
+            let next = addr.wrapping_add(instruction.size());
+            let mut label = |addr: u16, kind: LabelKind| {
+                let entry = kinds.entry(addr).or_insert(kind);
+                if kind > *entry {
+                    *entry = kind;
+                }
+            };
+            match instruction {
+                Instruction::Ret | Instruction::Exit | Instruction::Sys(_) => {},
+                Instruction::Jp(target) | Instruction::JpV0(target) => {
+                    label(target, LabelKind::Jump);
+                    pending.push(target);
+                },
+                Instruction::Call(target) => {
+                    label(target, LabelKind::Call);
+                    pending.push(target);
+                    pending.push(next);
+                },
+                Instruction::SeByte(..) | Instruction::SneByte(..) | Instruction::SeReg(..) |
+                Instruction::SneReg(..) | Instruction::Skp(_) | Instruction::Sknp(_) => {
+                    pending.push(next);
+                    let skipped = self.decode(next).map(|i| i.size()).unwrap_or(2);
+                    pending.push(next.wrapping_add(skipped));
+                },
+                Instruction::LdI(target) | Instruction::LdLongI(target) => {
+                    label(target, LabelKind::Data);
+                    pending.push(next);
+                },
+                _ => pending.push(next),
+            }
+        }
+
+        for (addr, kind) in kinds {
+            if !self.contains(addr) {
+                continue;
+            }
+            let name = match kind {
+                LabelKind::Start => "start".to_string(),
+                LabelKind::Call => format!("sub_{:03X}", addr),
+                LabelKind::Jump => format!("loc_{:03X}", addr),
+                LabelKind::Data => format!("data_{:03X}", addr),
+            };
+            self.labels.insert(addr, name);
+        }
+    }
+
+    /// The instructions found, by address.
+    pub fn instructions(&self) -> &BTreeMap<u16, Instruction> {
+        &self.code
+    }
+
+    /// The label given to `addr`, if it is the target of a jump, call or
+    /// index load.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|label| label.as_str())
+    }
+
+    fn format_address(&self, addr: u16) -> String {
+        match self.label(addr) {
+            Some(label) => label.to_string(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    /// Writes the disassembly as a listing. Each instruction or run of data
+    /// is commented with its address and bytes.
+    pub fn write_listing<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut addr = ROM_START as u32;
+        while addr < self.end() {
+            let pc = addr as u16;
+            if let Some(label) = self.label(pc) {
+                writeln!(out, "{}:", label)?;
+            }
+
+            if let Some(instruction) = self.code.get(&pc) {
+                let bytes: Vec<String> = (0..instruction.size() / 2)
+                    .map(|n| format!("{:02X}{:02X}", self.read(pc + n * 2), self.read(pc + n * 2 + 1)))
+                    .collect();
+                let text = instruction.format_with(|target| self.format_address(target));
+                write_line(out, &text, pc, &bytes.join(" "))?;
+                addr += instruction.size() as u32;
+                continue;
+            }
+
+            let mut data = vec![self.read(pc)];
+            addr += 1;
+            while addr < self.end() && data.len() < DATA_PER_LINE {
+                let next = addr as u16;
+                if self.code.contains_key(&next) || self.labels.contains_key(&next) {
+                    break;
+                }
+                data.push(self.read(next));
+                addr += 1;
+            }
+            let bytes: Vec<String> = data.iter().map(|b| format!("0x{:02X}", b)).collect();
+            write_line(out, &format!("DB {}", bytes.join(", ")), pc, "")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_line<W: Write>(out: &mut W, text: &str, addr: u16, bytes: &str) -> io::Result<()> {
+    let line = format!("    {}", text);
+    let padding = COMMENT_COLUMN.saturating_sub(line.len()).max(1);
+    let comment = format!("{:03X} {}", addr, bytes);
+    writeln!(out, "{}{}; {}", line, " ".repeat(padding), comment.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(program: &[u16]) -> Vec<u8> {
+        program.iter().flat_map(|word| vec![(word >> 8) as u8, *word as u8]).collect()
+    }
+
+    fn listing(program: &[u16]) -> String {
+        let mut out = Vec::new();
+        Disassembly::new(&rom(program), false).write_listing(&mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(|line| {
+            line.split(';').next().unwrap().trim_end().to_string()
+        }).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn labels_targets() {
+        let disasm = Disassembly::new(&rom(&[0x2206, 0xA20A, 0x1204, 0x00E0, 0x00EE, 0xF0F0]), false);
+        assert_eq!(disasm.label(0x200), Some("start"));
+        assert_eq!(disasm.label(0x204), Some("loc_204"));
+        assert_eq!(disasm.label(0x206), Some("sub_206"));
+        assert_eq!(disasm.label(0x20A), Some("data_20A"));
+        assert_eq!(disasm.label(0x202), None);
+    }
+
+    #[test]
+    fn detects_data() {
+        let program = [0xA204, 0x1202, 0x6001, 0x0203];
+        assert_eq!(listing(&program), "\
+start:
+    LD I, data_204
+loc_202:
+    JP loc_202
+data_204:
+    DB 0x60, 0x01, 0x02, 0x03");
+    }
+
+    #[test]
+    fn follows_skips() {
+        let disasm = Disassembly::new(&rom(&[0x3000, 0x00EE, 0x6001, 0x00EE]), false);
+        assert!(disasm.instructions().contains_key(&0x202));
+        assert!(disasm.instructions().contains_key(&0x204));
+        assert!(disasm.instructions().contains_key(&0x206));
+    }
+
+    #[test]
+    fn skips_over_long_instructions() {
+        let program = rom(&[0x3000, 0xF000, 0x0300, 0x00EE]);
+        let disasm = Disassembly::new(&program, true);
+        assert_eq!(disasm.instructions().get(&0x202), Some(&Instruction::LdLongI(0x300)));
+        assert!(!disasm.instructions().contains_key(&0x204));
+        assert!(disasm.instructions().contains_key(&0x206));
+    }
+
+    #[test]
+    fn decodes_the_end_of_memory() {
+        let program = vec![0x60; 0x10000 - ROM_START as usize];
+        let disasm = Disassembly::new(&program, true);
+        assert_eq!(disasm.instructions().get(&0xFFFE), Some(&Instruction::LdByte(0, 0x60)));
+        disasm.write_listing(&mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn comments_addresses_and_bytes() {
+        let mut out = Vec::new();
+        Disassembly::new(&rom(&[0x6312]), false).write_listing(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "start:\n    LD V3, 0x12             ; 200 6312\n");
+    }
+}
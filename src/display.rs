@@ -0,0 +1,275 @@
+use std::io::{self, Write};
+
+/// The width of the standard CHIP-8 screen.
+pub const LORES_WIDTH: usize = 64;
+/// The height of the standard CHIP-8 screen.
+pub const LORES_HEIGHT: usize = 32;
+/// The width of the SUPER-CHIP high resolution screen.
+pub const HIRES_WIDTH: usize = 128;
+/// The height of the SUPER-CHIP high resolution screen.
+pub const HIRES_HEIGHT: usize = 64;
+
+/// The screen, either 64x32 or, in SUPER-CHIP high resolution mode, 128x64.
+///
+/// Each pixel is a bitmask of the planes it is lit on. Plain CHIP-8 and
+/// SUPER-CHIP programs only ever draw to plane 1, while XO-CHIP programs can
+/// select plane 2 as well, giving four colours.
+pub struct Display {
+    width: usize,
+    height: usize,
+    screen: Vec<u8>,
+    planes: u8,
+    clip_sprites: bool,
+    dirty: bool,
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display {
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            screen: vec![0; LORES_WIDTH * LORES_HEIGHT],
+            planes: 1,
+            clip_sprites: false,
+            dirty: true,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_WIDTH
+    }
+
+    /// Switches between the 64x32 and 128x64 resolutions, clearing the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        if hires {
+            self.width = HIRES_WIDTH;
+            self.height = HIRES_HEIGHT;
+        } else {
+            self.width = LORES_WIDTH;
+            self.height = LORES_HEIGHT;
+        }
+        self.screen = vec![0; self.width * self.height];
+        self.dirty = true;
+    }
+
+    /// Sets whether sprite pixels beyond the right and bottom edges are
+    /// dropped, rather than wrapping around to the other side.
+    pub fn set_clip_sprites(&mut self, clip: bool) {
+        self.clip_sprites = clip;
+    }
+
+    /// The screen contents, row by row, one byte per pixel. Each byte is a
+    /// bitmask of the planes the pixel is lit on, so 0 to 3.
+    pub fn screen(&self) -> &[u8] {
+        &self.screen
+    }
+
+    /// Returns true if the screen has changed since the last `mark_clean`.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Records that the screen as it is now has been shown.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// The bitmask of planes that drawing, clearing and scrolling affect.
+    pub fn selected_planes(&self) -> u8 {
+        self.planes
+    }
+
+    /// Selects the planes later drawing, clearing and scrolling affect.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0x3;
+    }
+
+    /// Replaces the resolution, selected planes and screen contents, as when
+    /// loading a save state. `screen` must match the resolution's size.
+    pub(crate) fn restore(&mut self, hires: bool, planes: u8, screen: &[u8]) {
+        self.set_hires(hires);
+        self.select_planes(planes);
+        self.screen.copy_from_slice(screen);
+        self.dirty = true;
+    }
+
+    /// Clears the selected planes.
+    pub fn clear_screen(&mut self) {
+        let keep = !self.planes;
+        for pixel in self.screen.iter_mut() {
+            self.dirty |= *pixel & self.planes != 0;
+            *pixel &= keep;
+        }
+    }
+
+    fn toggle_pixel(&mut self, plane: u8, pixel: u8,  x: usize, y: usize) -> bool {
+        if pixel == 0 {
+            return false;
+        }
+        if self.clip_sprites && (x >= self.width || y >= self.height) {
+            return false;
+        }
+        let real_x = x % self.width;
+        let real_y = y % self.height;
+        let offset = (real_y * self.width) + real_x;
+        let flipped = self.screen[offset] & plane != 0;
+        self.screen[offset] ^= plane;
+        self.dirty = true;
+        flipped
+    }
+
+    /// XORs an 8 pixel wide sprite row onto `plane`, returning true if any lit
+    /// pixel was turned off. Coordinates past the edges of the screen are
+    /// clipped or wrapped, depending on `set_clip_sprites`.
+    pub fn draw_line(&mut self, plane: u8, line: u8, x: usize, y: usize) -> bool {
+        self.draw_bits(plane, (line as u16) << 8, 8, x, y)
+    }
+
+    /// XORs a 16 pixel wide SUPER-CHIP sprite row onto `plane`, returning true
+    /// if any lit pixel was turned off.
+    pub fn draw_wide_line(&mut self, plane: u8, line: u16, x: usize, y: usize) -> bool {
+        self.draw_bits(plane, line, 16, x, y)
+    }
+
+    fn draw_bits(&mut self, plane: u8, line: u16, width: usize, x: usize, y: usize) -> bool {
+        let mut flipped = false;
+        for n in 0..width {
+            let pixel = ((line << n) & 0x8000) >> 15;
+            flipped |= self.toggle_pixel(plane, pixel as u8, x + n, y);
+        }
+        flipped
+    }
+
+    /// Moves the selected planes of the pixel at `from` to `to`, or blanks
+    /// them at `to` if `from` is off screen.
+    fn shift_pixel(&mut self, to: usize, from: Option<usize>) {
+        let moved = match from {
+            Some(from) => self.screen[from] & self.planes,
+            None => 0,
+        };
+        let pixel = (self.screen[to] & !self.planes) | moved;
+        self.dirty |= pixel != self.screen[to];
+        self.screen[to] = pixel;
+    }
+
+    /// Scrolls the selected planes down by `rows`, blanking the rows uncovered
+    /// at the top.
+    pub fn scroll_down(&mut self, rows: usize) {
+        let shift = rows * self.width;
+        for n in (0..self.screen.len()).rev() {
+            let from = if n >= shift { Some(n - shift) } else { None };
+            self.shift_pixel(n, from);
+        }
+    }
+
+    /// Scrolls the selected planes up by `rows`, blanking the rows uncovered at
+    /// the bottom.
+    pub fn scroll_up(&mut self, rows: usize) {
+        let shift = rows * self.width;
+        let len = self.screen.len();
+        for n in 0..len {
+            let from = if n + shift < len { Some(n + shift) } else { None };
+            self.shift_pixel(n, from);
+        }
+    }
+
+    /// Scrolls the selected planes left by `columns`, blanking the columns
+    /// uncovered on the right.
+    pub fn scroll_left(&mut self, columns: usize) {
+        for y in 0..self.height {
+            let row = y * self.width;
+            for n in 0..self.width {
+                let from = if n + columns < self.width { Some(row + n + columns) } else { None };
+                self.shift_pixel(row + n, from);
+            }
+        }
+    }
+
+    /// Scrolls the selected planes right by `columns`, blanking the columns
+    /// uncovered on the left.
+    pub fn scroll_right(&mut self, columns: usize) {
+        for y in 0..self.height {
+            let row = y * self.width;
+            for n in (0..self.width).rev() {
+                let from = if n >= columns { Some(row + n - columns) } else { None };
+                self.shift_pixel(row + n, from);
+            }
+        }
+    }
+
+    /// Writes the screen as rows of characters: `.` for unlit pixels, `#` for
+    /// plane 1, `+` for plane 2 and `@` for both.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for row in self.screen.chunks(self.width) {
+            let line: String = row.iter().map(|&p| ['.', '#', '+', '@'][(p & 0x3) as usize]).collect();
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the screen as a plain (ASCII) PBM image, pixels lit on any plane
+    /// being black.
+    pub fn write_pbm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "P1\n{} {}", self.width, self.height)?;
+        for row in self.screen.chunks(self.width) {
+            let line: Vec<&str> = row.iter().map(|&p| if p != 0 { "1" } else { "0" }).collect();
+            writeln!(out, "{}", line.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(display: &Display, pbm: bool) -> Vec<String> {
+        let mut out = Vec::new();
+        if pbm {
+            display.write_pbm(&mut out).unwrap();
+        } else {
+            display.write_text(&mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap().lines().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn writes_text() {
+        let mut display = Display::new();
+        display.draw_line(1, 0b1100_0000, 0, 0);
+        display.draw_line(2, 0b0110_0000, 0, 0);
+        display.draw_line(1, 0b1000_0000, 63, 31);
+        let lines = dump(&display, false);
+        assert_eq!(lines.len(), 32);
+        assert_eq!(lines[0], format!("#@+{}", ".".repeat(61)));
+        assert_eq!(lines[31], format!("{}#", ".".repeat(63)));
+    }
+
+    #[test]
+    fn writes_pbm() {
+        let mut display = Display::new();
+        display.set_hires(true);
+        display.draw_line(1, 0b1000_0000, 0, 0);
+        display.draw_line(2, 0b1000_0000, 2, 0);
+        let lines = dump(&display, true);
+        assert_eq!(&lines[..2], &["P1", "128 64"]);
+        assert_eq!(lines.len(), 2 + 64);
+        assert!(lines[2].starts_with("1 0 1 0 "), "lit on any plane is black");
+        assert_eq!(lines[2].split(' ').count(), 128);
+        assert_eq!(lines[3], vec!["0"; 128].join(" "));
+    }
+}
@@ -0,0 +1,35 @@
+use std::error::Error;
+use std::fmt;
+
+/// Why a `Cpu` could not execute an instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CpuError {
+    /// The opcode at `address` is not a valid instruction.
+    UnknownOpcode { address: u16, opcode: u16 },
+    /// The opcode at `address` is a 0NNN call to a native machine code
+    /// routine, which cannot be emulated.
+    MachineCodeCall { address: u16, opcode: u16 },
+}
+
+/// The outcome of executing a single instruction.
+pub type StepResult = Result<(), CpuError>;
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::UnknownOpcode { address, opcode } =>
+                write!(f, "Unknown opcode {:04X} at {:03X}", opcode, address),
+            CpuError::MachineCodeCall { address, opcode } =>
+                write!(f, "Unsupported machine code call {:04X} at {:03X}", opcode, address),
+        }
+    }
+}
+
+impl Error for CpuError {
+    fn description(&self) -> &str {
+        match *self {
+            CpuError::UnknownOpcode { .. } => "unknown opcode",
+            CpuError::MachineCodeCall { .. } => "unsupported machine code call",
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+
+use keymap::Keymap;
+use keypad::KeyEvent;
+
+/// A controller button, laid out as on a typical modern gamepad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    /// The bottom face button: A on Xbox pads, cross on PlayStation ones.
+    South,
+    East,
+    North,
+    West,
+    LeftShoulder,
+    RightShoulder,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+const BUTTONS: [Button; 12] = [Button::South, Button::East, Button::North, Button::West, Button::LeftShoulder,
+                               Button::RightShoulder, Button::Select, Button::Start, Button::Up, Button::Down,
+                               Button::Left, Button::Right];
+
+impl Button {
+    /// The name keymaps bind the button by.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Button::South => "pad_south",
+            Button::East => "pad_east",
+            Button::North => "pad_north",
+            Button::West => "pad_west",
+            Button::LeftShoulder => "pad_l",
+            Button::RightShoulder => "pad_r",
+            Button::Select => "pad_select",
+            Button::Start => "pad_start",
+            Button::Up => "pad_up",
+            Button::Down => "pad_down",
+            Button::Left => "pad_left",
+            Button::Right => "pad_right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        BUTTONS.iter().cloned().find(|button| button.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Something a controller did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GamepadEvent {
+    Pressed(Button),
+    Released(Button),
+    Connected,
+    Disconnected,
+}
+
+/// A source of controller events, e.g. a platform gamepad library.
+pub trait GamepadBackend {
+    /// Returns the next event since the last call, or `None` once there
+    /// are none left.
+    fn next_event(&mut self) -> Option<GamepadEvent>;
+}
+
+/// A backend fed by hand, for tests and scripted input.
+#[derive(Clone, Debug, Default)]
+pub struct SyntheticGamepad {
+    events: VecDeque<GamepadEvent>,
+}
+
+impl SyntheticGamepad {
+    pub fn new() -> SyntheticGamepad {
+        SyntheticGamepad { events: VecDeque::new() }
+    }
+
+    /// Queues an event for the next `next_event`.
+    pub fn push(&mut self, event: GamepadEvent) {
+        self.events.push_back(event);
+    }
+}
+
+impl GamepadBackend for SyntheticGamepad {
+    fn next_event(&mut self) -> Option<GamepadEvent> {
+        self.events.pop_front()
+    }
+}
+
+/// Turns controller events into hex key events through a keymap's `pad_`
+/// bindings.
+pub struct Gamepad {
+    backend: Box<dyn GamepadBackend>,
+    held: Vec<Button>,
+}
+
+impl Gamepad {
+    pub fn new(backend: Box<dyn GamepadBackend>) -> Gamepad {
+        Gamepad { backend, held: Vec::new() }
+    }
+
+    /// Drains the backend, appending the key events its buttons are bound
+    /// to in `keymap`. Disconnecting a controller releases whatever it was
+    /// holding, so no key is left stuck down.
+    pub fn poll(&mut self, keymap: &Keymap, events: &mut Vec<KeyEvent>) {
+        while let Some(event) = self.backend.next_event() {
+            let released = match event {
+                GamepadEvent::Pressed(button) => {
+                    if !self.held.contains(&button) {
+                        self.held.push(button);
+                    }
+                    events.extend(keymap.hex_key(button.name()).map(KeyEvent::Pressed));
+                    continue;
+                },
+                GamepadEvent::Released(button) => {
+                    self.held.retain(|&held| held != button);
+                    vec![button]
+                },
+                GamepadEvent::Disconnected => self.held.drain(..).collect(),
+                GamepadEvent::Connected => continue,
+            };
+            for button in released {
+                events.extend(keymap.hex_key(button.name()).map(KeyEvent::Released));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gamepad(events: &[GamepadEvent]) -> Gamepad {
+        let mut backend = SyntheticGamepad::new();
+        for &event in events {
+            backend.push(event);
+        }
+        Gamepad::new(Box::new(backend))
+    }
+
+    #[test]
+    fn maps_buttons() {
+        let mut gamepad = gamepad(&[GamepadEvent::Connected, GamepadEvent::Pressed(Button::Up),
+                                    GamepadEvent::Pressed(Button::Start), GamepadEvent::Released(Button::Up)]);
+        let mut events = Vec::new();
+        gamepad.poll(&Keymap::default(), &mut events);
+        assert_eq!(events, vec![KeyEvent::Pressed(5), KeyEvent::Released(5)], "start is unbound");
+    }
+
+    #[test]
+    fn disconnecting_releases_buttons() {
+        let mut gamepad = gamepad(&[GamepadEvent::Pressed(Button::Left), GamepadEvent::Pressed(Button::South),
+                                    GamepadEvent::Disconnected]);
+        let mut events = Vec::new();
+        gamepad.poll(&Keymap::default(), &mut events);
+        assert_eq!(&events[2..], &[KeyEvent::Released(7), KeyEvent::Released(6)]);
+    }
+
+    #[test]
+    fn names() {
+        for &button in BUTTONS.iter() {
+            assert_eq!(Button::from_name(button.name()), Some(button));
+        }
+        assert_eq!(Button::from_name("pad_turbo"), None);
+    }
+}
@@ -0,0 +1,67 @@
+use audio::AudioOutput;
+use traits::Chip8System;
+
+/// A `Chip8System` with no window, no input and no frame pacing, for running
+/// ROMs as fast as possible on machines without a display.
+pub struct HeadlessSystem {
+    frames: u64,
+    frame_limit: Option<u64>,
+    audio: Option<Box<dyn AudioOutput>>,
+}
+
+impl Default for HeadlessSystem {
+    fn default() -> HeadlessSystem {
+        HeadlessSystem::new()
+    }
+}
+
+impl HeadlessSystem {
+    pub fn new() -> HeadlessSystem {
+        HeadlessSystem {
+            frames: 0,
+            frame_limit: None,
+            audio: None,
+        }
+    }
+
+    /// Creates a system that reports itself closed after `limit` frames.
+    pub fn with_frame_limit(limit: u64) -> HeadlessSystem {
+        HeadlessSystem {
+            frames: 0,
+            frame_limit: Some(limit),
+            audio: None,
+        }
+    }
+
+    /// Sends the machine's audio to `output`, e.g. a `WavWriter`.
+    pub fn set_audio_output(&mut self, output: Box<dyn AudioOutput>) {
+        self.audio = Some(output);
+    }
+
+    /// The number of frames run so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl Chip8System for HeadlessSystem {
+    fn render(&mut self, _: &[u8], _: usize, _: usize) {}
+
+    fn end_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    fn is_closed(&mut self) -> bool {
+        match self.frame_limit {
+            Some(limit) => self.frames >= limit,
+            None => false,
+        }
+    }
+
+    fn audio_output(&mut self) -> Option<&mut dyn AudioOutput> {
+        match self.audio {
+            Some(ref mut output) => Some(&mut **output),
+            None => None,
+        }
+    }
+}
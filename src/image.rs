@@ -0,0 +1,411 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use palette::Palette;
+
+/// An RGBA image of the screen, drawn on the CPU, e.g. for screenshots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    /// Four bytes per pixel, row by row from the top left.
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Draws a `width` by `height` screen, as `Display::screen` gives it,
+    /// with each pixel a `scale` by `scale` square in the palette's colours.
+    pub fn from_screen(screen: &[u8], width: usize, height: usize, scale: usize, palette: &Palette) -> Image {
+        let mut pixels = Vec::with_capacity(width * height * scale * scale * 4);
+        let mut line = Vec::with_capacity(width * scale * 4);
+        for row in screen.chunks(width).take(height) {
+            line.clear();
+            for &pixel in row {
+                let [r, g, b] = palette.colour(pixel);
+                for _ in 0..scale {
+                    line.extend_from_slice(&[r, g, b, 0xFF]);
+                }
+            }
+            for _ in 0..scale {
+                pixels.extend_from_slice(&line);
+            }
+        }
+        Image { width: width * scale, height: height * scale, pixels }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The pixels' RGBA bytes, row by row from the top left.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// The RGBA colour of the pixel at (`x`, `y`).
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * self.width + x) * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_png(&mut out)?;
+        out.flush()
+    }
+
+    /// Writes the image as a PNG.
+    pub fn write_png<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&u32_be(self.width as u32));
+        header.extend_from_slice(&u32_be(self.height as u32));
+        header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8-bit RGBA, deflate, no interlacing
+        write_chunk(out, b"IHDR", &header)?;
+        write_chunk(out, b"IDAT", &zlib(&self.filtered()))?;
+        write_chunk(out, b"IEND", &[])
+    }
+
+    /// The rows with their PNG filters applied. Screens are mostly runs of
+    /// one colour and rows repeated by the scaling, which the Sub and Up
+    /// filters turn into runs of zeros for `zlib` to squash.
+    fn filtered(&self) -> Vec<u8> {
+        let stride = self.width * 4;
+        let mut data = Vec::with_capacity((stride + 1) * self.height);
+        for y in 0..self.height {
+            let row = &self.pixels[y * stride..(y + 1) * stride];
+            if y > 0 && row == &self.pixels[(y - 1) * stride..y * stride] {
+                data.push(2); // Up
+                data.extend(row.iter().map(|_| 0));
+            } else {
+                data.push(1); // Sub
+                data.extend(row.iter().enumerate().map(|(i, &byte)| {
+                    if i < 4 { byte } else { byte.wrapping_sub(row[i - 4]) }
+                }));
+            }
+        }
+        data
+    }
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&u32_be(data.len() as u32))?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    out.write_all(&u32_be(crc))
+}
+
+/// The lengths a deflate length code stands for, from 257 up: the shortest
+/// and the number of extra bits giving how much longer.
+const LENGTHS: [(usize, u8); 29] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0), (11, 1), (13, 1), (15, 1), (17, 1),
+    (19, 2), (23, 2), (27, 2), (31, 2), (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4),
+    (115, 4), (131, 5), (163, 5), (195, 5), (227, 5), (258, 0),
+];
+
+/// Compresses `data` as a zlib stream: one deflate block with the fixed
+/// Huffman codes, where every repeated byte becomes part of a run (a match
+/// one byte back). Nothing cleverer is needed for screens.
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    bits.write_bits(0x78, 8); // Deflate, 32 KiB window
+    bits.write_bits(0x01, 8); // No preset dictionary, and a multiple of 31
+    bits.write_bits(1, 1); // The final block
+    bits.write_bits(1, 2); // Fixed Huffman codes
+
+    let mut i = 0;
+    while i < data.len() {
+        bits.write_literal(data[i] as u16);
+        i += 1;
+        let mut run = data[i..].iter().take_while(|&&byte| byte == data[i - 1]).count();
+        while run >= 3 {
+            let length = ::std::cmp::min(run, 258);
+            // Stop a run short rather than leave 1 or 2 bytes too few to match.
+            let length = if run - length > 0 && run - length < 3 { length - 3 } else { length };
+            bits.write_length(length);
+            bits.write_bits(0, 5); // Distance code 0, one byte back
+            i += length;
+            run -= length;
+        }
+    }
+    bits.write_literal(256); // End of block
+
+    let mut out = bits.finish();
+    out.extend_from_slice(&u32_be(adler32(data)));
+    out
+}
+
+/// Packs bits into bytes from the least significant bit up, as deflate does.
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), current: 0, count: 0 }
+    }
+
+    /// Writes the low `count` bits of `value`, least significant first.
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.current |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.current as u8);
+            self.current >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which deflate packs most significant bit first.
+    fn write_code(&mut self, code: u32, count: u32) {
+        let reversed = (0..count).fold(0, |reversed, bit| reversed << 1 | (code >> bit) & 1);
+        self.write_bits(reversed, count);
+    }
+
+    /// Writes a literal byte, the end of block (256) or a length code, in
+    /// the fixed Huffman code.
+    fn write_literal(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        if symbol < 144 {
+            self.write_code(0x30 + symbol, 8);
+        } else if symbol < 256 {
+            self.write_code(0x190 + symbol - 144, 9);
+        } else if symbol < 280 {
+            self.write_code(symbol - 256, 7);
+        } else {
+            self.write_code(0xC0 + symbol - 280, 8);
+        }
+    }
+
+    fn write_length(&mut self, length: usize) {
+        let code = LENGTHS.iter().rposition(|&(base, _)| base <= length).unwrap();
+        let (base, extra) = LENGTHS[code];
+        self.write_literal(257 + code as u16);
+        self.write_bits((length - base) as u32, extra as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.current as u8);
+        }
+        self.bytes
+    }
+}
+
+fn crc32<'a, I: Iterator<Item = &'a u8>>(data: I) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn u32_be(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_the_screen() {
+        let palette = Palette::new([0, 0, 0], [0xFF, 0x80, 0x00]);
+        let image = Image::from_screen(&[0, 1, 0, 0, 0, 2], 3, 2, 2, &palette);
+        assert_eq!((image.width(), image.height()), (6, 4));
+        assert_eq!(image.pixels().len(), 6 * 4 * 4);
+        assert_eq!(image.pixel(0, 0), [0, 0, 0, 0xFF]);
+        assert_eq!(image.pixel(2, 1), [0xFF, 0x80, 0x00, 0xFF]);
+        assert_eq!(image.pixel(3, 1), [0xFF, 0x80, 0x00, 0xFF]);
+        assert_eq!(image.pixel(4, 0), [0, 0, 0, 0xFF]);
+        assert_eq!(image.pixel(5, 3), [0xAA, 0x55, 0x00, 0xFF], "plane 2's shade");
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND".iter()), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn writes_pngs() {
+        let image = Image::from_screen(&[1; 64 * 32], 64, 32, 8, &Palette::default());
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 2, 0, 0, 0, 1, 0]);
+        assert_eq!(&png[png.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+        assert!(png.len() < image.pixels().len() / 100, "a blank screen compresses well, not to {} bytes", png.len());
+    }
+
+    #[test]
+    fn pngs_decode_to_the_pixels() {
+        let screen: Vec<u8> = (0..64 * 32).map(|i| if i % 7 < 3 { (i % 4) as u8 } else { 0 }).collect();
+        for &(screen, scale) in &[(&screen[..], 3), (&[0; 64 * 32][..], 8)] {
+            let image = Image::from_screen(screen, 64, 32, scale, &Palette::default());
+            let mut png = Vec::new();
+            image.write_png(&mut png).unwrap();
+            assert_eq!(decode_png(&png), (image.width(), image.height(), image.pixels().to_vec()));
+        }
+    }
+
+    fn u32_at(bytes: &[u8]) -> u32 {
+        bytes[..4].iter().fold(0, |value, &byte| value << 8 | byte as u32)
+    }
+
+    /// Reads back an 8-bit RGBA PNG, checking its chunks' CRCs.
+    fn decode_png(png: &[u8]) -> (usize, usize, Vec<u8>) {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let (mut width, mut height, mut idat) = (0, 0, Vec::new());
+        let mut pos = 8;
+        while pos < png.len() {
+            let len = u32_at(&png[pos..]) as usize;
+            let kind = &png[pos + 4..pos + 8];
+            let data = &png[pos + 8..pos + 8 + len];
+            assert_eq!(u32_at(&png[pos + 8 + len..]), crc32(png[pos + 4..pos + 8 + len].iter()));
+            if kind == b"IHDR" {
+                width = u32_at(data) as usize;
+                height = u32_at(&data[4..]) as usize;
+                assert_eq!(&data[8..], &[8, 6, 0, 0, 0]);
+            } else if kind == b"IDAT" {
+                idat.extend_from_slice(data);
+            }
+            pos += len + 12;
+        }
+
+        let data = inflate(&idat);
+        let stride = width * 4;
+        assert_eq!(data.len(), (stride + 1) * height);
+        let mut pixels: Vec<u8> = Vec::with_capacity(stride * height);
+        for (y, row) in data.chunks(stride + 1).enumerate() {
+            for i in 0..stride {
+                let left = if i >= 4 { pixels[y * stride + i - 4] } else { 0 };
+                let up = if y > 0 { pixels[(y - 1) * stride + i] } else { 0 };
+                let up_left = if y > 0 && i >= 4 { pixels[(y - 1) * stride + i - 4] } else { 0 };
+                let predicted = match row[0] {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    4 => {
+                        let p = left as i16 + up as i16 - up_left as i16;
+                        let (pa, pb, pc) = ((p - left as i16).abs(), (p - up as i16).abs(), (p - up_left as i16).abs());
+                        if pa <= pb && pa <= pc { left } else if pb <= pc { up } else { up_left }
+                    },
+                    filter => panic!("unknown filter {}", filter),
+                };
+                pixels.push(row[i + 1].wrapping_add(predicted));
+            }
+        }
+        (width, height, pixels)
+    }
+
+    /// Decompresses a zlib stream of stored and fixed Huffman blocks, which
+    /// is enough to read back what `zlib` writes.
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(u32_at(&[0, 0, stream[0], stream[1]]) % 31, 0);
+        let mut bits = BitReader { data: &stream[2..], pos: 0 };
+        let mut out = Vec::new();
+        loop {
+            let last = bits.read(1) == 1;
+            match bits.read(2) {
+                0 => {
+                    bits.skip_to_byte();
+                    let len = bits.read(16);
+                    assert_eq!(bits.read(16), !len & 0xFFFF);
+                    for _ in 0..len {
+                        out.push(bits.read(8) as u8);
+                    }
+                },
+                1 => loop {
+                    let symbol = bits.fixed_literal();
+                    if symbol < 256 {
+                        out.push(symbol as u8);
+                        continue;
+                    } else if symbol == 256 {
+                        break;
+                    }
+                    let (base, extra) = LENGTHS[symbol as usize - 257];
+                    let length = base + bits.read(extra as u32) as usize;
+                    let code = bits.code(5);
+                    let extra = |code: u32| if code < 4 { 0 } else { (code - 2) / 2 };
+                    let distance = 1 + (0..code).map(|code| 1 << extra(code)).sum::<usize>();
+                    let distance = distance + bits.read(extra(code)) as usize;
+                    for _ in 0..length {
+                        let byte = out[out.len() - distance];
+                        out.push(byte);
+                    }
+                },
+                kind => panic!("unsupported block type {}", kind),
+            }
+            if last {
+                break;
+            }
+        }
+        bits.skip_to_byte();
+        assert_eq!(u32_at(&bits.data[bits.pos / 8..]), adler32(&out));
+        out
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for n in 0..count {
+                let bit = self.data[self.pos / 8] >> (self.pos % 8) & 1;
+                value |= (bit as u32) << n;
+                self.pos += 1;
+            }
+            value
+        }
+
+        fn skip_to_byte(&mut self) {
+            self.pos += (8 - self.pos % 8) % 8;
+        }
+
+        /// Reads a Huffman code, most significant bit first.
+        fn code(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |code, _| code << 1 | self.read(1))
+        }
+
+        fn fixed_literal(&mut self) -> u32 {
+            let code = self.code(7);
+            if code < 0x18 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.read(1);
+            if code < 0xC0 {
+                return code - 0x30;
+            } else if code < 0xC8 {
+                return 280 + code - 0xC0;
+            }
+            144 + (code << 1 | self.read(1)) - 0x190
+        }
+    }
+}
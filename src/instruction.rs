@@ -0,0 +1,289 @@
+use std::fmt;
+
+/// A decoded instruction. Variants are named after their mnemonics; `x` and
+/// `y` operands are register numbers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00E0
+    Cls,
+    /// 00EE
+    Ret,
+    /// 00CN, SUPER-CHIP
+    Scd(u8),
+    /// 00DN, XO-CHIP
+    Scu(u8),
+    /// 00FB, SUPER-CHIP
+    Scr,
+    /// 00FC, SUPER-CHIP
+    Scl,
+    /// 00FD, SUPER-CHIP
+    Exit,
+    /// 00FE, SUPER-CHIP
+    Low,
+    /// 00FF, SUPER-CHIP
+    High,
+    /// 0NNN, a call to a machine code routine
+    Sys(u16),
+    /// 1NNN
+    Jp(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN
+    SeByte(u8, u8),
+    /// 4XNN
+    SneByte(u8, u8),
+    /// 5XY0
+    SeReg(u8, u8),
+    /// 5XY2, XO-CHIP
+    Save(u8, u8),
+    /// 5XY3, XO-CHIP
+    Load(u8, u8),
+    /// 6XNN
+    LdByte(u8, u8),
+    /// 7XNN
+    AddByte(u8, u8),
+    /// 8XY0
+    LdReg(u8, u8),
+    /// 8XY1
+    Or(u8, u8),
+    /// 8XY2
+    And(u8, u8),
+    /// 8XY3
+    Xor(u8, u8),
+    /// 8XY4
+    AddReg(u8, u8),
+    /// 8XY5
+    Sub(u8, u8),
+    /// 8XY6
+    Shr(u8, u8),
+    /// 8XY7
+    Subn(u8, u8),
+    /// 8XYE
+    Shl(u8, u8),
+    /// 9XY0
+    SneReg(u8, u8),
+    /// ANNN
+    LdI(u16),
+    /// BNNN
+    JpV0(u16),
+    /// CXNN
+    Rnd(u8, u8),
+    /// DXYN
+    Drw(u8, u8, u8),
+    /// EX9E
+    Skp(u8),
+    /// EXA1
+    Sknp(u8),
+    /// F000 NNNN, XO-CHIP
+    LdLongI(u16),
+    /// FN01, XO-CHIP
+    Plane(u8),
+    /// F002, XO-CHIP
+    Audio,
+    /// FX07
+    LdVxDt(u8),
+    /// FX0A
+    LdVxK(u8),
+    /// FX15
+    LdDtVx(u8),
+    /// FX18
+    LdStVx(u8),
+    /// FX1E
+    AddIVx(u8),
+    /// FX29
+    LdFVx(u8),
+    /// FX30, SUPER-CHIP
+    LdHfVx(u8),
+    /// FX33
+    LdBVx(u8),
+    /// FX3A, XO-CHIP
+    Pitch(u8),
+    /// FX55
+    LdIVx(u8),
+    /// FX65
+    LdVxI(u8),
+    /// FX75, SUPER-CHIP
+    LdRVx(u8),
+    /// FX85, SUPER-CHIP
+    LdVxR(u8),
+    /// Anything else.
+    Unknown(u16),
+}
+
+impl Instruction {
+    /// Decodes `opcode`. `next` is the word after it, which is only used as
+    /// the operand of XO-CHIP's four byte F000 instruction. XO-CHIP
+    /// instructions decode as `Unknown` unless `xo_chip` is set.
+    pub fn decode(opcode: u16, next: u16, xo_chip: bool) -> Instruction {
+        let nibbles = (
+            (opcode >> 12) as u8,
+            (opcode >> 8) as u8 & 0xF,
+            (opcode >> 4) as u8 & 0xF,
+            opcode as u8 & 0xF,
+        );
+        let nnn = opcode & 0xFFF;
+        let nn = opcode as u8;
+
+        match nibbles {
+            (0, 0, 0xE, 0) => Instruction::Cls,
+            (0, 0, 0xE, 0xE) => Instruction::Ret,
+            (0, 0, 0xC, n) => Instruction::Scd(n),
+            (0, 0, 0xD, n) if xo_chip => Instruction::Scu(n),
+            (0, 0, 0xF, 0xB) => Instruction::Scr,
+            (0, 0, 0xF, 0xC) => Instruction::Scl,
+            (0, 0, 0xF, 0xD) => Instruction::Exit,
+            (0, 0, 0xF, 0xE) => Instruction::Low,
+            (0, 0, 0xF, 0xF) => Instruction::High,
+            (0, _, _, _) => Instruction::Sys(nnn),
+            (1, _, _, _) => Instruction::Jp(nnn),
+            (2, _, _, _) => Instruction::Call(nnn),
+            (3, x, _, _) => Instruction::SeByte(x, nn),
+            (4, x, _, _) => Instruction::SneByte(x, nn),
+            (5, x, y, 0) => Instruction::SeReg(x, y),
+            (5, x, y, 2) if xo_chip => Instruction::Save(x, y),
+            (5, x, y, 3) if xo_chip => Instruction::Load(x, y),
+            (6, x, _, _) => Instruction::LdByte(x, nn),
+            (7, x, _, _) => Instruction::AddByte(x, nn),
+            (8, x, y, 0) => Instruction::LdReg(x, y),
+            (8, x, y, 1) => Instruction::Or(x, y),
+            (8, x, y, 2) => Instruction::And(x, y),
+            (8, x, y, 3) => Instruction::Xor(x, y),
+            (8, x, y, 4) => Instruction::AddReg(x, y),
+            (8, x, y, 5) => Instruction::Sub(x, y),
+            (8, x, y, 6) => Instruction::Shr(x, y),
+            (8, x, y, 7) => Instruction::Subn(x, y),
+            (8, x, y, 0xE) => Instruction::Shl(x, y),
+            (9, x, y, 0) => Instruction::SneReg(x, y),
+            (0xA, _, _, _) => Instruction::LdI(nnn),
+            (0xB, _, _, _) => Instruction::JpV0(nnn),
+            (0xC, x, _, _) => Instruction::Rnd(x, nn),
+            (0xD, x, y, n) => Instruction::Drw(x, y, n),
+            (0xE, x, 9, 0xE) => Instruction::Skp(x),
+            (0xE, x, 0xA, 1) => Instruction::Sknp(x),
+            (0xF, 0, 0, 0) if xo_chip => Instruction::LdLongI(next),
+            (0xF, n, 0, 1) if xo_chip => Instruction::Plane(n),
+            (0xF, 0, 0, 2) if xo_chip => Instruction::Audio,
+            (0xF, x, 0, 7) => Instruction::LdVxDt(x),
+            (0xF, x, 0, 0xA) => Instruction::LdVxK(x),
+            (0xF, x, 1, 5) => Instruction::LdDtVx(x),
+            (0xF, x, 1, 8) => Instruction::LdStVx(x),
+            (0xF, x, 1, 0xE) => Instruction::AddIVx(x),
+            (0xF, x, 2, 9) => Instruction::LdFVx(x),
+            (0xF, x, 3, 0) => Instruction::LdHfVx(x),
+            (0xF, x, 3, 3) => Instruction::LdBVx(x),
+            (0xF, x, 3, 0xA) if xo_chip => Instruction::Pitch(x),
+            (0xF, x, 5, 5) => Instruction::LdIVx(x),
+            (0xF, x, 6, 5) => Instruction::LdVxI(x),
+            (0xF, x, 7, 5) => Instruction::LdRVx(x),
+            (0xF, x, 8, 5) => Instruction::LdVxR(x),
+            _ => Instruction::Unknown(opcode),
+        }
+    }
+
+    /// The instruction's size in bytes: 4 for F000 NNNN, otherwise 2.
+    pub fn size(&self) -> u16 {
+        match *self {
+            Instruction::LdLongI(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Formats the instruction's mnemonic, using `address` to format any
+    /// address operand. This lets a disassembler print labels in place of
+    /// raw addresses.
+    pub fn format_with<F: Fn(u16) -> String>(&self, address: F) -> String {
+        match *self {
+            Instruction::Cls => "CLS".to_string(),
+            Instruction::Ret => "RET".to_string(),
+            Instruction::Scd(n) => format!("SCD {}", n),
+            Instruction::Scu(n) => format!("SCU {}", n),
+            Instruction::Scr => "SCR".to_string(),
+            Instruction::Scl => "SCL".to_string(),
+            Instruction::Exit => "EXIT".to_string(),
+            Instruction::Low => "LOW".to_string(),
+            Instruction::High => "HIGH".to_string(),
+            Instruction::Sys(addr) => format!("SYS {}", address(addr)),
+            Instruction::Jp(addr) => format!("JP {}", address(addr)),
+            Instruction::Call(addr) => format!("CALL {}", address(addr)),
+            Instruction::SeByte(x, nn) => format!("SE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SneByte(x, nn) => format!("SNE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SeReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            Instruction::Save(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+            Instruction::Load(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdByte(x, nn) => format!("LD V{:X}, 0x{:02X}", x, nn),
+            Instruction::AddByte(x, nn) => format!("ADD V{:X}, 0x{:02X}", x, nn),
+            Instruction::LdReg(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(addr) => format!("LD I, {}", address(addr)),
+            Instruction::JpV0(addr) => format!("JP V0, {}", address(addr)),
+            Instruction::Rnd(x, nn) => format!("RND V{:X}, 0x{:02X}", x, nn),
+            Instruction::Drw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => format!("SKP V{:X}", x),
+            Instruction::Sknp(x) => format!("SKNP V{:X}", x),
+            Instruction::LdLongI(addr) => format!("LONG I, {}", address(addr)),
+            Instruction::Plane(n) => format!("PLANE {}", n),
+            Instruction::Audio => "AUDIO".to_string(),
+            Instruction::LdVxDt(x) => format!("LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => format!("LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => format!("LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => format!("LD ST, V{:X}", x),
+            Instruction::AddIVx(x) => format!("ADD I, V{:X}", x),
+            Instruction::LdFVx(x) => format!("LD F, V{:X}", x),
+            Instruction::LdHfVx(x) => format!("LD HF, V{:X}", x),
+            Instruction::LdBVx(x) => format!("LD B, V{:X}", x),
+            Instruction::Pitch(x) => format!("PITCH V{:X}", x),
+            Instruction::LdIVx(x) => format!("LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => format!("LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => format!("LD R, V{:X}", x),
+            Instruction::LdVxR(x) => format!("LD V{:X}, R", x),
+            Instruction::Unknown(opcode) => format!("DW 0x{:04X}", opcode),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format_with(|addr| format!("0x{:03X}", addr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(Instruction::decode(0x6312, 0, false), Instruction::LdByte(3, 0x12));
+        assert_eq!(Instruction::decode(0xD015, 0, false), Instruction::Drw(0, 1, 5));
+        assert_eq!(Instruction::decode(0x8126, 0, false), Instruction::Shr(1, 2));
+        assert_eq!(Instruction::decode(0x0123, 0, false), Instruction::Sys(0x123));
+        assert_eq!(Instruction::decode(0x8008, 0, false), Instruction::Unknown(0x8008));
+    }
+
+    #[test]
+    fn decode_xo_chip() {
+        assert_eq!(Instruction::decode(0xF000, 0x1234, false), Instruction::Unknown(0xF000));
+        assert_eq!(Instruction::decode(0xF000, 0x1234, true), Instruction::LdLongI(0x1234));
+        assert_eq!(Instruction::decode(0xF000, 0x1234, true).size(), 4);
+        assert_eq!(Instruction::decode(0x5122, 0, true), Instruction::Save(1, 2));
+        assert_eq!(Instruction::decode(0xF201, 0, true), Instruction::Plane(2));
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(Instruction::LdByte(3, 0x12).to_string(), "LD V3, 0x12");
+        assert_eq!(Instruction::Drw(0, 1, 5).to_string(), "DRW V0, V1, 5");
+        assert_eq!(Instruction::LdI(0x2A0).to_string(), "LD I, 0x2A0");
+        assert_eq!(Instruction::LdVxI(0xA).to_string(), "LD VA, [I]");
+        assert_eq!(Instruction::Unknown(0x8008).to_string(), "DW 0x8008");
+        assert_eq!(Instruction::Call(0x300).format_with(|_| "draw".to_string()), "CALL draw");
+    }
+}
@@ -0,0 +1,279 @@
+use std::path::Path;
+
+use config::{self, ConfigError, ConfigFile, Entry};
+
+/// The host keys for hex keys 0 to F on each preset layout. Each layout
+/// puts the hex keypad's 4x4 grid on the same physical keys, those under
+/// 1234/QWER/ASDF/ZXCV on a QWERTY keyboard.
+const QWERTY: [&str; 16] = ["x", "1", "2", "3", "q", "w", "e", "a", "s", "d", "z", "c", "4", "r", "f", "v"];
+const AZERTY: [&str; 16] = ["x", "1", "2", "3", "a", "z", "e", "q", "s", "d", "w", "c", "4", "r", "f", "v"];
+const DVORAK: [&str; 16] = ["q", "1", "2", "3", "apostrophe", "comma", "period", "a", "o", "e", "semicolon",
+                            "j", "4", "p", "u", "k"];
+
+/// Controller bindings shared by every layout: the d-pad presses the hex
+/// keys under WASD on QWERTY, and the south and east buttons those under E
+/// and Q.
+const PAD: [(&str, u8); 6] = [("pad_up", 5), ("pad_left", 7), ("pad_down", 8), ("pad_right", 9),
+                             ("pad_south", 6), ("pad_east", 4)];
+
+/// A keyboard layout to base a keymap on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    Qwerty,
+    Azerty,
+    Dvorak,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "qwerty" => Some(Layout::Qwerty),
+            "azerty" => Some(Layout::Azerty),
+            "dvorak" => Some(Layout::Dvorak),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> &'static [&'static str; 16] {
+        match *self {
+            Layout::Qwerty => &QWERTY,
+            Layout::Azerty => &AZERTY,
+            Layout::Dvorak => &DVORAK,
+        }
+    }
+}
+
+/// Which host keys press which hex keys.
+///
+/// Host keys are named in lower case, letters and digits by themselves and
+/// other keys by name, e.g. `space`, `comma` or `numpad7`. Controller
+/// buttons are named as `Button::name` gives, e.g. `pad_up` or `pad_south`.
+/// Names are independent of any windowing library; frontends translate
+/// their own key codes to them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: Vec<(String, u8)>,
+}
+
+impl Keymap {
+    pub fn new(layout: Layout) -> Keymap {
+        let mut bindings: Vec<(String, u8)> =
+            layout.keys().iter().enumerate().map(|(hex, name)| (name.to_string(), hex as u8)).collect();
+        bindings.extend(PAD.iter().map(|&(name, hex)| (name.to_string(), hex)));
+        Keymap { bindings }
+    }
+
+    /// Makes `names` the only keyboard keys pressing `hex` if any are given,
+    /// and likewise for controller buttons, so that rebinding the keyboard
+    /// leaves the controller alone. An empty list unbinds `hex` entirely.
+    /// The keys named stop pressing whatever they pressed before.
+    pub fn bind(&mut self, hex: u8, names: &[String]) {
+        let names: Vec<String> = names.iter().map(|name| name.to_lowercase()).collect();
+        let pads = names.is_empty() || names.iter().any(|name| is_pad(name));
+        let keys = names.is_empty() || names.iter().any(|name| !is_pad(name));
+        self.bindings.retain(|&(ref name, key)| {
+            !names.contains(name) && (key != hex || if is_pad(name) { !pads } else { !keys })
+        });
+        self.bindings.extend(names.into_iter().map(|name| (name, hex)));
+    }
+
+    /// The hex key the host key `name` presses, if any.
+    pub fn hex_key(&self, name: &str) -> Option<u8> {
+        self.bindings.iter().find(|(bound, _)| bound.eq_ignore_ascii_case(name)).map(|&(_, hex)| hex)
+    }
+
+    /// The host keys pressing `hex`.
+    pub fn host_keys(&self, hex: u8) -> Vec<&str> {
+        self.bindings.iter().filter(|&&(_, key)| key == hex).map(|(name, _)| &name[..]).collect()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::new(Layout::Qwerty)
+    }
+}
+
+fn is_pad(name: &str) -> bool {
+    name.starts_with("pad_")
+}
+
+/// Bindings made by one part of a keymap file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Section {
+    layout: Option<Layout>,
+    bindings: Vec<(u8, Vec<String>)>,
+}
+
+/// A keymap file: a layout and bindings used for every ROM, optionally
+/// overridden for particular ROMs.
+///
+/// The file is a small subset of TOML. At the top, `layout` picks the
+/// preset to start from, and a hex digit names the host key or keys
+/// pressing that hex key. `[rom."name"]` starts overrides for the ROM file
+/// called `name`:
+///
+/// ```toml
+/// layout = "azerty"
+/// 5 = ["z", "up"]
+///
+/// [rom."brix.ch8"]
+/// 4 = ["left", "pad_left"]
+/// 6 = ["right", "pad_right"]
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeymapConfig {
+    global: Section,
+    roms: Vec<(String, Section)>,
+}
+
+impl KeymapConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<KeymapConfig, ConfigError> {
+        KeymapConfig::from_file(&ConfigFile::load(path.as_ref())?)
+    }
+
+    pub fn parse(text: &str) -> Result<KeymapConfig, ConfigError> {
+        KeymapConfig::from_file(&ConfigFile::parse("<input>", text)?)
+    }
+
+    fn from_file(file: &ConfigFile) -> Result<KeymapConfig, ConfigError> {
+        let mut config = KeymapConfig::default();
+        for &(line, ref entry) in file.entries() {
+            let (key, value) = match *entry {
+                Entry::Section(ref name) => {
+                    let rom = parse_section(name).ok_or_else(|| file.error(line, "Expected a [rom.\"name\"] section"))?;
+                    config.roms.push((rom, Section::default()));
+                    continue;
+                },
+                Entry::Setting(ref key, ref value) => (key, value),
+            };
+
+            let section = match config.roms.last_mut() {
+                Some(&mut (_, ref mut section)) => section,
+                None => &mut config.global,
+            };
+            let names = config::parse_strings(value)
+                .ok_or_else(|| file.error(line, "Expected a quoted key name or a list of them"))?;
+            if key == "layout" {
+                let layout = match names.len() {
+                    1 => Layout::from_name(&names[0]),
+                    _ => None,
+                };
+                let layout = layout.ok_or_else(|| file.error(line, "Layout must be qwerty, azerty or dvorak"))?;
+                section.layout = Some(layout);
+            } else {
+                let hex = match u8::from_str_radix(key, 16) {
+                    Ok(hex) if key.len() == 1 => hex,
+                    _ => {
+                        let message = format!("Unknown setting '{}', expected layout or a hex key", key);
+                        return Err(file.error(line, message));
+                    },
+                };
+                section.bindings.push((hex, names));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The keymap for the ROM file called `rom`. Its overrides, if any, are
+    /// applied on top of the bindings for every ROM.
+    pub fn keymap_for(&self, rom: &str) -> Keymap {
+        let overrides: Vec<&Section> = self.roms.iter()
+            .filter(|(name, _)| name == rom)
+            .map(|(_, section)| section)
+            .collect();
+        let layout = overrides.iter().rev().filter_map(|section| section.layout).next()
+            .or(self.global.layout)
+            .unwrap_or(Layout::Qwerty);
+
+        let mut keymap = Keymap::new(layout);
+        for section in Some(&self.global).into_iter().chain(overrides) {
+            for &(hex, ref names) in section.bindings.iter() {
+                keymap.bind(hex, names);
+            }
+        }
+        keymap
+    }
+}
+
+/// Parses `rom."name"` or `rom.name`, returning the name.
+fn parse_section(section: &str) -> Option<String> {
+    if !section.starts_with("rom.") {
+        return None;
+    }
+    let name = section["rom.".len()..].trim();
+    match config::parse_string(name) {
+        Some(name) => Some(name),
+        None if !name.is_empty() && !name.contains('"') => Some(name.to_string()),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let qwerty = Keymap::default();
+        assert_eq!(qwerty.hex_key("x"), Some(0));
+        assert_eq!(qwerty.hex_key("V"), Some(0xF));
+        assert_eq!(Keymap::new(Layout::Azerty).hex_key("z"), Some(5));
+        assert_eq!(Keymap::new(Layout::Dvorak).hex_key("comma"), Some(5));
+        assert_eq!(Layout::from_name("colemak"), None);
+    }
+
+    #[test]
+    fn binding_replaces() {
+        let mut keymap = Keymap::default();
+        keymap.bind(5, &["up".to_string(), "Q".to_string()]);
+        assert_eq!(keymap.hex_key("w"), None);
+        assert_eq!(keymap.hex_key("up"), Some(5));
+        assert_eq!(keymap.hex_key("q"), Some(5), "q no longer presses 4");
+        assert_eq!(keymap.host_keys(4), vec!["pad_east"]);
+        assert_eq!(keymap.hex_key("pad_up"), Some(5), "the controller is untouched");
+
+        keymap.bind(5, &["pad_north".to_string()]);
+        assert_eq!(keymap.host_keys(5), vec!["up", "q", "pad_north"]);
+        keymap.bind(5, &[]);
+        assert_eq!(keymap.host_keys(5), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn per_rom_overrides() {
+        let config = KeymapConfig::parse(r#"
+# Shared by every ROM
+layout = "azerty"
+5 = ["z", "up"]   # also the arrow key
+
+[rom."brix.ch8"]
+4 = "left"
+
+[rom.pong]
+layout = "qwerty"
+"#).unwrap();
+
+        let keymap = config.keymap_for("tetris.ch8");
+        assert_eq!(keymap.hex_key("up"), Some(5));
+        assert_eq!(keymap.hex_key("a"), Some(4));
+
+        let keymap = config.keymap_for("brix.ch8");
+        assert_eq!(keymap.hex_key("left"), Some(4));
+        assert_eq!(keymap.hex_key("a"), None);
+        assert_eq!(keymap.hex_key("up"), Some(5));
+
+        let keymap = config.keymap_for("pong");
+        assert_eq!(keymap.hex_key("q"), Some(4));
+        assert_eq!(keymap.hex_key("up"), Some(5));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text| KeymapConfig::parse(text).unwrap_err();
+        assert_eq!(error("layout = \"qwerty\"\nlayout = \"colemak\"").line, 2);
+        assert_eq!(error("G = \"g\"").message, "Unknown setting 'G', expected layout or a hex key");
+        assert_eq!(error("5 = w").line, 1);
+        assert_eq!(error("[keys]").line, 1);
+        assert_eq!(error("5").to_string(), "<input>:1: Expected 'key = value'");
+    }
+}
@@ -0,0 +1,154 @@
+/// A change to one of the sixteen hex keys.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8),
+}
+
+/// The state of the hex keypad, built up from the press and release events
+/// hosts push into it.
+///
+/// Besides the keys currently held, the keypad remembers which keys have
+/// gone down since `clear_presses`, so that a press and release falling
+/// between two instructions is not lost to FX0A.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Keypad {
+    held: u16,
+    pressed: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad { held: 0, pressed: 0 }
+    }
+
+    pub fn press(&mut self, key: u8) {
+        let bit = 1 << (key & 0xF);
+        if self.held & bit == 0 {
+            self.pressed |= bit;
+        }
+        self.held |= bit;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.held &= !(1 << (key & 0xF));
+    }
+
+    pub fn apply(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Pressed(key) => self.press(key),
+            KeyEvent::Released(key) => self.release(key),
+        }
+    }
+
+    pub fn is_held(&self, key: u8) -> bool {
+        self.held & (1 << (key & 0xF)) != 0
+    }
+
+    /// The held keys as a bitmask, bit N set for key N.
+    pub fn held(&self) -> u16 {
+        self.held
+    }
+
+    /// Presses and releases keys so that exactly those in `mask` are held.
+    pub fn set_held(&mut self, mask: u16) {
+        for key in 0..16 {
+            if mask & (1 << key) != 0 {
+                self.press(key);
+            } else {
+                self.release(key);
+            }
+        }
+    }
+
+    /// The keys pressed since `clear_presses` and not yet taken, as a
+    /// bitmask.
+    pub fn presses(&self) -> u16 {
+        self.pressed
+    }
+
+    /// Replays a frame's worth of key events, as summed up by the keys that
+    /// went down during it and those held at its end. Each key in `presses`
+    /// is released, if held, and pressed again, then the keys are pressed
+    /// or released so that exactly those in `held` are held.
+    pub fn replay(&mut self, held: u16, presses: u16) {
+        for key in 0..16 {
+            if presses & (1 << key) != 0 {
+                self.apply(KeyEvent::Released(key));
+                self.apply(KeyEvent::Pressed(key));
+            }
+        }
+        for key in 0..16 {
+            self.apply(if held & (1 << key) != 0 { KeyEvent::Pressed(key) } else { KeyEvent::Released(key) });
+        }
+    }
+
+    /// Returns the lowest key pressed since the last call or
+    /// `clear_presses`, forgetting that press.
+    pub fn take_press(&mut self) -> Option<u8> {
+        if self.pressed == 0 {
+            return None;
+        }
+        let key = self.pressed.trailing_zeros() as u8;
+        self.pressed &= !(1 << key);
+        Some(key)
+    }
+
+    pub fn clear_presses(&mut self) {
+        self.pressed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_held_keys() {
+        let mut keypad = Keypad::new();
+        keypad.apply(KeyEvent::Pressed(3));
+        keypad.apply(KeyEvent::Pressed(0xF));
+        keypad.apply(KeyEvent::Released(3));
+        assert!(!keypad.is_held(3));
+        assert!(keypad.is_held(0xF));
+        assert_eq!(keypad.held(), 0x8000);
+    }
+
+    #[test]
+    fn remembers_presses() {
+        let mut keypad = Keypad::new();
+        keypad.press(9);
+        keypad.release(9);
+        keypad.press(2);
+        keypad.press(2);
+        assert_eq!(keypad.take_press(), Some(2));
+        assert_eq!(keypad.take_press(), Some(9));
+        assert_eq!(keypad.take_press(), None);
+
+        keypad.press(4);
+        keypad.clear_presses();
+        assert_eq!(keypad.take_press(), None);
+    }
+
+    #[test]
+    fn set_held() {
+        let mut keypad = Keypad::new();
+        keypad.press(1);
+        keypad.clear_presses();
+        keypad.set_held(0x0006);
+        assert_eq!(keypad.held(), 0x0006);
+        assert_eq!(keypad.take_press(), Some(2));
+        assert_eq!(keypad.take_press(), None);
+    }
+
+    #[test]
+    fn replay() {
+        let mut keypad = Keypad::new();
+        keypad.press(1);
+        keypad.press(7);
+        keypad.clear_presses();
+        keypad.replay(0x0081, 0x0003);
+        assert_eq!(keypad.held(), 0x0081);
+        assert_eq!(keypad.presses(), 0x0003, "a held key pressed again counts, one still held doesn't");
+    }
+}
@@ -0,0 +1,57 @@
+//! A CHIP-8 interpreter core.
+//!
+//! The interpreter is driven through `Cpu`, which owns the machine state and a
+//! host implementing `Chip8System`. The host is responsible for presenting the
+//! screen, providing input and playing audio; everything else happens inside
+//! the library.
+
+extern crate rand;
+
+mod traits;
+mod asm;
+mod audio;
+mod config;
+mod cpu;
+mod debugger;
+mod disasm;
+mod display;
+mod error;
+mod gamepad;
+mod headless;
+mod image;
+mod instruction;
+mod keymap;
+mod keypad;
+mod memory;
+mod movie;
+mod palette;
+mod quirks;
+mod random;
+mod registers;
+mod rewind;
+mod state;
+
+pub use traits::Chip8System;
+pub use asm::{assemble, assemble_file, AsmError};
+pub use audio::{AudioOutput, ToneGenerator, WavWriter, DEFAULT_PITCH, DEFAULT_VOLUME, DEFAULT_PATTERN_PITCH};
+pub use config::ConfigError;
+pub use cpu::{Cpu, DEFAULT_CLOCK_SPEED, TIMER_FREQUENCY};
+pub use debugger::{Action, Debugger, StopReason};
+pub use disasm::Disassembly;
+pub use display::{Display, LORES_WIDTH, LORES_HEIGHT, HIRES_WIDTH, HIRES_HEIGHT};
+pub use error::{CpuError, StepResult};
+pub use gamepad::{Button, Gamepad, GamepadBackend, GamepadEvent, SyntheticGamepad};
+pub use headless::HeadlessSystem;
+pub use image::Image;
+pub use instruction::Instruction;
+pub use keymap::{Keymap, KeymapConfig, Layout};
+pub use keypad::{KeyEvent, Keypad};
+pub use memory::{Memory, WatchHit, load_rom, ROM_START, MAX_ROM_SIZE, MEMORY_SIZE, XO_CHIP_MEMORY_SIZE,
+                 FONT_ADDRESS, BIG_FONT_ADDRESS};
+pub use movie::{Movie, MoviePlayer, MovieRecorder, MOVIE_VERSION};
+pub use palette::{parse_colour, Colour, Palette};
+pub use quirks::{Profile, Quirks, DEFAULT_PROFILE};
+pub use random::{RandomMode, RandomSource, VipRandom, XorShiftSource};
+pub use registers::{Registers, STACK_SIZE};
+pub use rewind::Rewinder;
+pub use state::{rom_hash, StateError, STATE_VERSION};
@@ -1,555 +1,597 @@
 extern crate rustc_serialize;
 extern crate docopt;
-extern crate rand;
+extern crate rust_chip8;
+extern crate cpal;
+extern crate gilrs;
 
 #[macro_use]
 extern crate glium;
 
-mod traits;
-use self::traits::*;
+use rust_chip8::{Action, AudioOutput, Chip8System, Colour, Cpu, CpuError, Debugger, Disassembly, Gamepad,
+                 HeadlessSystem, Image, Keymap, KeymapConfig, Layout, Movie, MoviePlayer, MovieRecorder, Palette,
+                 Profile, RandomMode, Rewinder, StateError, StopReason, WavWriter, MEMORY_SIZE, ROM_START,
+                 TIMER_FREQUENCY, XO_CHIP_MEMORY_SIZE};
 
 use docopt::Docopt;
 use std::fs::File;
-use std::io::{self, Write, Read};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
 mod chip_gl;
-use self::chip_gl::GliumRenderer;
+use self::chip_gl::{GliumRenderer, Hotkey, Persistence, RendererOptions, Scaling};
 
+mod chip_audio;
+use self::chip_audio::CpalOutput;
 
-const USAGE: &'static str = "
+mod chip_gamepad;
+use self::chip_gamepad::GilrsBackend;
+
+mod pacer;
+use self::pacer::FramePacer;
+
+/// The sample rate audio is written to WAV files at.
+const WAV_SAMPLE_RATE: u32 = 44100;
+
+
+const USAGE: &str = "
 rust-chip8
 
 Usage:
-    rust-chip8 <file>
+    rust-chip8 [options] <file>
+    rust-chip8 disasm [--profile <name>] <file>
+    rust-chip8 asm [--output <rom>] <file>
     rust-chip8 (-h | --help)
 
 Options:
-    -h --help   Show this screen
+    -h --help         Show this screen
+    --output <rom>    Where asm writes the ROM, by default the source file
+                      with a .ch8 extension
+    --profile <name>  Interpreter to behave as: vip, chip-48, schip or xo-chip.
+                      xo-chip also enables its instructions and 64 KiB of
+                      memory [default: schip]
+    --ips <n>         Instructions executed per second [default: 720]
+    --seed <n>        Seed for CXNN's random numbers, random by default
+    --random <name>   Random number generator: xorshift, or vip for the COSMAC
+                      VIP interpreter's routine [default: xorshift]
+    --load-state <file>
+                      Start from a save state. F5 and F9 save and load
+                      this file, rather than <file>.state
+    --rewind <secs>   Seconds of history kept for rewinding by holding
+                      backspace, 0 to disable [default: 10]
+    --record <movie>  Record the keys pressed on every frame to a movie file.
+                      Rewinding and loading states are disabled meanwhile
+    --replay <movie>  Replay a recorded movie headlessly, with the profile,
+                      speed and random numbers it was recorded with
+    --keymap <file>   Key and controller bindings: qwerty, azerty, dvorak, or
+                      a keymap file with per-ROM overrides [default: qwerty]
+    --no-gamepad      Ignore game controllers
+    --palette <name>  Colours: classic, green, amber, lcd, octo, or a palette
+                      file [default: classic]
+    --foreground <colour>
+                      Colour of lit pixels, as #RRGGBB, overriding the palette
+    --background <colour>
+                      Colour of unlit pixels, as #RRGGBB
+    --persistence <mode>
+                      Blend frames to hide flicker: off, decay to let pixels
+                      fade out, or or to show pixels lit on either of the
+                      last two frames [default: off]
+    --decay <n>       Fraction of its brightness an unlit pixel keeps each
+                      frame with decay persistence [default: 0.6]
+    --shader <name>   Post-process shader: plain, scanlines, crt, bloom, grid,
+                      or a GLSL fragment shader file [default: plain]
+    --scaling <mode>  Fitting the screen to the window: integer for whole
+                      multiples of its size, aspect to keep its shape, or
+                      stretch [default: integer]
+    --scale <n>       Window size, in window pixels per CHIP-8 pixel at low
+                      resolution, and screenshot size, in image pixels per
+                      CHIP-8 pixel [default: 8]
+    --fullscreen      Start fullscreen. F11 switches in and out at any time
+    --debug           Start paused in the interactive debugger
+    --strict          Stop with a register dump on an invalid instruction,
+                      rather than logging it and carrying on
+    --unthrottled     Run as fast as possible instead of at 60 frames per second
+    --pitch <hz>      Pitch of the sound timer's tone [default: 440]
+    --volume <v>      Volume of the tone, from 0.0 to 1.0 [default: 0.25]
+    --mute            Play no sound
+    --wav <file>      Write the sound to a WAV file instead of the sound card
+    --headless        Run without a window and print the final screen
+    --frames <n>      Frames to run in headless mode [default: 600]
+    --until-halt      Stop headless mode early once the program jumps to itself
+    --dump <format>   Headless screen output, either text or pbm [default: text]
+    --screenshot-at <frame>
+                      Save a PNG of the screen at the end of this frame in
+                      headless mode, named after <file> with the frame number
+                      and a .png extension. F12 takes one in the window
 ";
 
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
+    cmd_disasm: bool,
+    cmd_asm: bool,
     arg_file: String,
+    flag_output: Option<String>,
+    flag_profile: String,
+    flag_ips: u32,
+    flag_seed: Option<u64>,
+    flag_random: String,
+    flag_load_state: Option<String>,
+    flag_rewind: u32,
+    flag_record: Option<String>,
+    flag_replay: Option<String>,
+    flag_keymap: String,
+    flag_no_gamepad: bool,
+    flag_palette: String,
+    flag_foreground: Option<String>,
+    flag_background: Option<String>,
+    flag_persistence: String,
+    flag_decay: f32,
+    flag_shader: String,
+    flag_scaling: String,
+    flag_scale: u32,
+    flag_fullscreen: bool,
+    flag_debug: bool,
+    flag_strict: bool,
+    flag_unthrottled: bool,
+    flag_pitch: f32,
+    flag_volume: f32,
+    flag_mute: bool,
+    flag_wav: Option<String>,
+    flag_headless: bool,
+    flag_frames: u64,
+    flag_until_halt: bool,
+    flag_dump: DumpFormat,
+    flag_screenshot_at: Option<u64>,
+}
+
+#[derive(Debug, RustcDecodable)]
+enum DumpFormat {
+    Text,
+    Pbm,
 }
 
 fn main() {
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
+    let profile = parse_profile(&args);
 
-    let f = File::open(args.arg_file).unwrap();
-    let mut rom: Vec<u8> = Vec::new();
-    let _ = f.take(0x1000 - 0x200).read_to_end(&mut rom).unwrap();
-    let system = GliumRenderer::new();
-    let mut cpu = Cpu::new(rom, system);
-    loop {
-        if cpu.run() { break; }
-    }
-}
-
-struct Cpu<T: Chip8System> {
-    disp: Display,
-    mem: Memory,
-    regs: Registers,
-    system: T,
-    wait_on_input: Option<u8>,
-}
-
-impl<T: Chip8System> Cpu<T> {
-    fn new(rom: Vec<u8>, system: T) -> Cpu<T> {
-        Cpu {
-            disp: Display::new(),
-            mem: Memory::new_with_rom(rom),
-            regs: Registers::new(),
-            system: system,
-            wait_on_input: None,
-        }
-    }
-
-    fn run(&mut self) -> bool {
-        let mut draw_countdown = 60000; //No idea
-        loop {
-            if let Some(reg) = self.wait_on_input {
-                if let Some(key) = self.system.get_input().first() {
-                    self.regs.set_data(reg, *key);
-                    self.wait_on_input = None;
-                }
-            } else {
-                let opcode  = self.read_opcode();
-                match opcode {
-                    (0, 0, 0xE, 0) => self.clear_screen(),
-                    (0, 0, 0xE, 0xE) => self.ret(),
-                    (0, _, _, _) => {}, //RCA program
-                    (1, a, b, c) => self.jump(Self::join_three(a,b,c)),
-                    (2, a, b, c) => self.sub(Self::join_three(a,b,c)),
-                    (3, a, b, c) => self.skip_if(a, Self::join_two(b,c)),
-                    (4, a, b, c) => self.skip_if_not(a, Self::join_two(b,c)),
-                    (5, a, b, 0) => self.skip_if_reg(a, b),
-                    (6, a, b, c) => self.set(a, Self::join_two(b,c)),
-                    (7, a, b, c) => self.add(a, Self::join_two(b,c)),
-                    (8, a, b, 0) => self.set_reg(a, b),
-                    (8, a, b, 1) => self.or_reg(a, b),
-                    (8, a, b, 2) => self.and_reg(a,b),
-                    (8, a, b, 3) => self.xor_reg(a,b),
-                    (8, a, b, 4) => self.add_reg(a,b),
-                    (8, a, b, 5) => self.cmp_reg(a,b),
-                    (8, a, b, 6) => self.shift_right_reg(a,b),
-                    (8, a, b, 7) => self.sub_reg(a,b),
-                    (8, a, b, 0xE) => self.shift_left_reg(a,b),
-                    (9, a, b, 0) => self.skip_if_not_reg(a,b),
-                    (0xA, a, b, c) => self.set_index(Self::join_three(a,b,c)),
-                    (0xB, a, b, c) => self.jump_offset(Self::join_three(a,b,c)),
-                    (0xC, a, b, c) => self.random(a, Self::join_two(b,c)),
-                    (0xD, a, b, c) => self.draw_sprite(a,b,c),
-                    (0xE, a, 9, 0xE) => self.skip_if_key(a),
-                    (0xE, a, 0xA, 1) => self.skip_if_not_key(a),
-                    (0xF, a, 0, 7) => self.set_from_delay_timer(a),
-                    (0xF, a, 0, 0xA) => self.wait_for_key(a),
-                    (0xF, a, 1, 5) => self.set_delay_timer(a),
-                    (0xF, a, 1, 8) => self.set_sound_timer(a),
-                    (0xF, a, 1, 0xE) => self.add_to_index(a),
-                    (0xF, a, 2, 9) => self.set_index_to_character(a),
-                    (0xF, a, 3, 3) => self.store_bcd(a),
-                    (0xF, a, 5, 5) => self.store_to_index(a),
-                    (0xF, a, 6, 5) => self.fill_from_index(a),
-                    _ => {},
-                };
-            }
-            draw_countdown -= 1;
-            if draw_countdown == 0 {
-                if self.regs.delay_timer != 0 { self.regs.delay_timer -= 1; }
-                if self.regs.sound_timer != 0 { self.regs.sound_timer -= 1; }
-                self.system.render(&self.disp.screen);
-                break;
-            }
-        }
-
-        self.system.is_closed()
+    if args.cmd_asm {
+        run_assembler(&args);
+        return;
     }
 
-    fn clear_screen(&mut self) {
-        self.disp.clear_screen();
-    }
+    let rom = File::open(&args.arg_file).and_then(rust_chip8::load_rom).unwrap_or_else(|err| {
+        eprintln!("Cannot read ROM from {}: {}", args.arg_file, err);
+        process::exit(1)
+    });
 
-    fn skip_if(&mut self, reg: u8, value: u8) {
-        if self.regs.get_data(reg) == value {
-            self.regs.address = self.regs.address.wrapping_add(2);
-        }
-    }
-
-    fn skip_if_not(&mut self, reg: u8, value: u8) {
-        if self.regs.get_data(reg) != value {
-            self.regs.address = self.regs.address.wrapping_add(2);
-        }
+    if args.cmd_disasm {
+        let disassembly = Disassembly::new(&rom, profile == Profile::XoChip);
+        let stdout = io::stdout();
+        disassembly.write_listing(&mut stdout.lock()).unwrap();
+        return;
     }
 
-    fn skip_if_reg(&mut self, reg_a: u8, reg_b: u8) {
-        if self.regs.get_data(reg_a) == self.regs.get_data(reg_b) {
-            self.regs.address = self.regs.address.wrapping_add(2);
-        }
+    let memory_size = if profile == Profile::XoChip { XO_CHIP_MEMORY_SIZE } else { MEMORY_SIZE };
+    if rom.len() > memory_size - ROM_START as usize {
+        eprintln!("{} is too large for the {} profile's memory", args.arg_file, profile.name());
+        process::exit(1);
     }
 
-    fn set(&mut self, reg: u8, value: u8) {
-        self.regs.set_data(reg, value);
+    if args.flag_record.is_some() && args.flag_load_state.is_some() {
+        docopt::Error::Argv("A movie must be recorded from power on, not a save state".to_string()).exit();
     }
 
-    fn add(&mut self, reg: u8, value: u8) {
-        let reg_val = self.regs.get_data(reg);
-        self.regs.set_data(reg, reg_val.wrapping_add(value));
+    if args.flag_replay.is_some() && args.flag_load_state.is_some() {
+        docopt::Error::Argv("A movie is replayed from power on, not a save state".to_string()).exit();
     }
 
-    fn set_reg(&mut self, reg_a: u8, reg_b: u8) {
-        let val = self.regs.get_data(reg_b);
-        self.regs.set_data(reg_a, val);
+    if args.flag_scale == 0 {
+        docopt::Error::Argv("--scale must be at least 1".to_string()).exit();
     }
 
-    fn or_reg(&mut self, reg_a: u8, reg_b: u8) {
-        let val_left = self.regs.get_data(reg_a);
-        let val_right = self.regs.get_data(reg_b);
-        self.regs.set_data(reg_a, val_left | val_right);
+    if args.flag_headless || args.flag_replay.is_some() {
+        run_headless(rom, profile, &args);
+        return;
     }
 
-    fn and_reg(&mut self, reg_a: u8, reg_b: u8) {
-        let val_left = self.regs.get_data(reg_a);
-        let val_right = self.regs.get_data(reg_b);
-        self.regs.set_data(reg_a, val_left & val_right);
-    }
+    let palette = load_palette(&args);
 
-    fn xor_reg(&mut self, reg_a: u8, reg_b: u8) {
-        let val_left = self.regs.get_data(reg_a);
-        let val_right = self.regs.get_data(reg_b);
-        self.regs.set_data(reg_a, val_left ^ val_right);
+    let mut system = GliumRenderer::new(RendererOptions {
+        keymap: load_keymap(&args),
+        gamepad: open_gamepad(&args),
+        palette,
+        persistence: parse_persistence(&args),
+        shader: load_shader(&args),
+        scaling: parse_scaling(&args),
+        scale: args.flag_scale,
+        fullscreen: args.flag_fullscreen,
+    }).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    if let Some(output) = open_audio(&args, true) {
+        system.set_audio_output(output);
     }
 
-    fn add_reg(&mut self, reg_a: u8, reg_b: u8) {                
-        let val_left = self.regs.get_data(reg_b);
-        let val_right = self.regs.get_data(reg_a);
-        if (val_left as u16) + (val_right as u16) > 255 {
-            self.regs.set_data(0xF, 1);
-        } else {
-            self.regs.set_data(0xF, 0);
+    let path = match args.flag_record {
+        Some(ref path) => path,
+        None => {
+            run_window(create_cpu(rom, system, profile, &args), &args, &palette);
+            return;
         }
-        
-        self.regs.set_data(reg_a, val_left.wrapping_add(val_right));
+    };
+    let movie = Movie::new(rust_chip8::rom_hash(&rom), profile, args.flag_ips, 0);
+    let mut cpu = create_cpu(rom, MovieRecorder::new(system, movie), profile, &args);
+    let seed = cpu.seed();
+    cpu.system_mut().movie_mut().seed = seed;
+    cpu.system_mut().movie_mut().random = parse_random(&args);
+    let cpu = run_window(cpu, &args, &palette);
+    match File::create(path).and_then(|mut f| cpu.system().movie().write(&mut f)) {
+        Ok(()) => eprintln!("Recorded {} frames to {}", cpu.system().movie().len(), path),
+        Err(err) => eprintln!("Cannot write movie to {}: {}", path, err),
     }
+}
 
-    fn cmp_reg(&mut self, reg_a: u8, reg_b: u8) {
-        let val_left = self.regs.get_data(reg_a);
-        let val_right = self.regs.get_data(reg_b);
-        if val_left > val_right {
-            self.regs.set_data(0xF, 1);
-        } else {
-            self.regs.set_data(0xF, 0);
-        }
+/// The window's renderer, whether or not it is wrapped to record a movie.
+trait Window: Chip8System {
+    fn renderer(&mut self) -> &mut GliumRenderer;
+}
 
-        self.regs.set_data(reg_a, val_left.wrapping_sub(val_right));
+impl Window for GliumRenderer {
+    fn renderer(&mut self) -> &mut GliumRenderer {
+        self
     }
+}
 
-    fn shift_right_reg(&mut self, reg_a: u8, _: u8) {
-        let val = self.regs.get_data(reg_a);
-        self.regs.set_data(0xF, val & 1);
-        self.regs.set_data(reg_a, val.wrapping_shr(1));
+impl Window for MovieRecorder<GliumRenderer> {
+    fn renderer(&mut self) -> &mut GliumRenderer {
+        self.inner_mut()
     }
+}
 
-    fn sub_reg(&mut self, reg_a: u8, reg_b: u8) {
-        let val_left = self.regs.get_data(reg_b);
-        let val_right = self.regs.get_data(reg_a);
-        if val_left > val_right {
-            self.regs.set_data(0xF, 1);
+/// Runs the machine in the window until it is closed, handling rewinding
+/// and hotkeys.
+fn run_window<S: Window>(mut cpu: Cpu<S>, args: &Args, palette: &Palette) -> Cpu<S> {
+    if args.flag_debug {
+        return run_debugger(cpu, !args.flag_unthrottled);
+    }
+
+    let state_path = match args.flag_load_state {
+        Some(ref path) => PathBuf::from(path),
+        None => Path::new(&args.arg_file).with_extension("state"),
+    };
+    // Going back in time would make the recorded keys meaningless.
+    let recording = args.flag_record.is_some();
+    let mut rewinder = Rewinder::new(if recording { 0 } else { args.flag_rewind });
+    let mut pacer = FramePacer::new(TIMER_FREQUENCY);
+    loop {
+        if !recording && cpu.system_mut().renderer().is_rewinding() {
+            rewinder.rewind(&mut cpu);
+            if cpu.refresh() { break; }
         } else {
-            self.regs.set_data(0xF, 0);
+            if run_frame(&mut cpu) { break; }
+            if !recording && args.flag_rewind > 0 { rewinder.record(&cpu); }
         }
-
-        self.regs.set_data(reg_a, val_left.wrapping_sub(val_right));
-    }
-
-    fn shift_left_reg(&mut self, reg_a: u8, _: u8) {
-        let val = self.regs.get_data(reg_a);
-        let carry = val & 0x80;
-        if carry != 0 {
-            self.regs.set_data(0xF, 1);
-        } else {
-            self.regs.set_data(0xF, 0);
+        for hotkey in cpu.system_mut().renderer().take_hotkeys() {
+            match hotkey {
+                Hotkey::SaveState => save_state(&cpu, &state_path),
+                Hotkey::LoadState if recording => eprintln!("Cannot load states while recording"),
+                Hotkey::LoadState => load_state(&mut cpu, &state_path),
+                Hotkey::Screenshot => save_screenshot(&cpu, args, palette),
+            }
         }
-
-        self.regs.set_data(reg_a, val.wrapping_shl(1));
+        if !args.flag_unthrottled { pacer.wait(); }
     }
+    cpu
+}
 
-    fn skip_if_not_reg(&mut self, reg_a: u8, reg_b: u8) {
-        if self.regs.get_data(reg_a) != self.regs.get_data(reg_b) {
-            self.regs.address = self.regs.address.wrapping_add(2);
-        }
+fn save_state<T: Chip8System>(cpu: &Cpu<T>, path: &Path) {
+    match File::create(path).and_then(|mut f| cpu.save_state(&mut f)) {
+        Ok(()) => eprintln!("Saved state to {}", path.display()),
+        Err(err) => eprintln!("Cannot save state to {}: {}", path.display(), err),
     }
+}
 
-    fn set_index(&mut self, value: u16) {
-        self.regs.index = value;
+fn load_state<T: Chip8System>(cpu: &mut Cpu<T>, path: &Path) {
+    let result = File::open(path)
+        .map_err(StateError::from)
+        .and_then(|mut f| cpu.load_state(&mut f));
+    match result {
+        Ok(()) => eprintln!("Loaded state from {}", path.display()),
+        Err(err) => eprintln!("Cannot load state from {}: {}", path.display(), err),
     }
+}
 
-    fn jump_offset(&mut self, addr: u16) {
-        self.regs.address = (self.regs.get_data(0) as u16).wrapping_add(addr);
+/// Saves a PNG of the screen named after the ROM and the frame, e.g.
+/// `pong.600.png`.
+fn save_screenshot<T: Chip8System>(cpu: &Cpu<T>, args: &Args, palette: &Palette) {
+    let display = cpu.display();
+    let scale = args.flag_scale as usize;
+    let image = Image::from_screen(display.screen(), display.width(), display.height(), scale, palette);
+    let path = Path::new(&args.arg_file).with_extension(format!("{}.png", cpu.frames()));
+    match image.save_png(&path) {
+        Ok(()) => eprintln!("Saved screenshot to {}", path.display()),
+        Err(err) => eprintln!("Cannot save screenshot to {}: {}", path.display(), err),
     }
+}
 
-    fn random(&mut self, reg: u8, value: u8) {
-        self.regs.set_data(reg, rand::random::<u8>() & value);
+fn run_assembler(args: &Args) {
+    let rom = rust_chip8::assemble_file(&args.arg_file).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    let output = match args.flag_output {
+        Some(ref output) => PathBuf::from(output),
+        None => Path::new(&args.arg_file).with_extension("ch8"),
+    };
+    if let Err(err) = File::create(&output).and_then(|mut f| f.write_all(&rom)) {
+        eprintln!("Cannot write ROM to {}: {}", output.display(), err);
+        process::exit(1);
     }
+}
 
-    fn draw_sprite(&mut self, reg_a: u8, reg_b: u8, rows: u8) {
-        let mut flipped = false;
-        for n in 0..rows {
-            flipped |= self.disp.draw_line(self.mem.read(self.regs.index.wrapping_add(n as u16)), self.regs.get_data(reg_a), self.regs.get_data(reg_b).wrapping_add(n));
+fn run_headless(rom: Vec<u8>, profile: Profile, args: &Args) {
+    let path = match args.flag_replay {
+        Some(ref path) => path,
+        None => {
+            let mut system = HeadlessSystem::with_frame_limit(args.flag_frames);
+            if let Some(output) = open_audio(args, false) {
+                system.set_audio_output(output);
+            }
+            finish_headless(create_cpu(rom, system, profile, args), args);
+            return;
         }
+    };
+
+    let movie = File::open(path).and_then(|f| Movie::read(BufReader::new(f))).unwrap_or_else(|err| {
+        eprintln!("Cannot read movie from {}: {}", path, err);
+        process::exit(1)
+    });
+    if movie.rom_hash != rust_chip8::rom_hash(&rom) {
+        eprintln!("{} was recorded with a different ROM", path);
+        process::exit(1);
+    }
+    let mut system = HeadlessSystem::new();
+    if let Some(output) = open_audio(args, false) {
+        system.set_audio_output(output);
+    }
+    let (profile, clock_speed, seed, random) = (movie.profile, movie.clock_speed, movie.seed, movie.random);
+    let mut cpu = create_cpu(rom, MoviePlayer::new(system, movie), profile, args);
+    cpu.set_clock_speed(clock_speed);
+    cpu.set_random_source(random.source(seed));
+    cpu.set_seed(seed);
+    finish_headless(cpu, args);
+}
 
-        self.regs.set_data(0xF, if flipped { 1 } else { 0 });
-    }
-
-    fn skip_if_key(&mut self, reg: u8) {
-        let key = self.regs.get_data(reg);
-        if self.system.get_input().contains(&key) {
-            self.regs.address = self.regs.address.wrapping_add(2);
+/// Runs a headless machine to the end and prints its screen.
+fn finish_headless<T: Chip8System>(mut cpu: Cpu<T>, args: &Args) {
+    if args.flag_debug {
+        cpu = run_debugger(cpu, false);
+    } else {
+        let palette = load_palette(args);
+        loop {
+            let closed = run_frame(&mut cpu);
+            if args.flag_screenshot_at == Some(cpu.frames()) {
+                save_screenshot(&cpu, args, &palette);
+            }
+            if closed { break; }
+            if args.flag_until_halt && cpu.is_halted() { break; }
         }
-    }
-
-    fn skip_if_not_key(&mut self, reg: u8) {
-        let key = self.regs.get_data(reg);
-        if !self.system.get_input().contains(&key) {
-            self.regs.address = self.regs.address.wrapping_add(2);
+        if let Some(frame) = args.flag_screenshot_at {
+            if cpu.frames() < frame {
+                eprintln!("Stopped at frame {}, before the screenshot at frame {}", cpu.frames(), frame);
+            }
         }
     }
 
-    fn set_from_delay_timer(&mut self, reg: u8) {
-        let val = self.regs.delay_timer;
-        self.regs.set_data(reg, val);
-    }
-
-    fn wait_for_key(&mut self, reg: u8) {
-        self.wait_on_input = Some(reg);
-    }
-
-    fn set_delay_timer(&mut self, reg: u8) {
-        self.regs.delay_timer = self.regs.get_data(reg);
-    }
-
-    fn set_sound_timer(&mut self, reg: u8) {
-        self.regs.sound_timer = self.regs.get_data(reg);
-    }
-
-    fn add_to_index(&mut self, reg: u8) {
-        self.regs.index = self.regs.index.wrapping_add(self.regs.get_data(reg) as u16);
-    }
-
-    fn set_index_to_character(&mut self, reg: u8) {
-        self.regs.index = self.regs.get_data(reg) as u16 * 5
-    }
-
-    fn store_bcd(&mut self, reg: u8) {
-        let val = self.regs.get_data(reg);
-
-        let hundreds = val / 100;
-        let tens = (val % 100) / 10;
-        let ones = val % 10;
-
-        self.mem.write(self.regs.index, hundreds);
-        self.mem.write(self.regs.index.wrapping_add(1), tens);
-        self.mem.write(self.regs.index.wrapping_add(2), ones);
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    match args.flag_dump {
+        DumpFormat::Text => cpu.display().write_text(&mut handle).unwrap(),
+        DumpFormat::Pbm => cpu.display().write_pbm(&mut handle).unwrap(),
     }
+}
 
-    fn store_to_index(&mut self, reg: u8) {
-        for n in 0..reg {
-            self.mem.write(self.regs.index.wrapping_add(n as u16), self.regs.get_data(n));
+/// Reads debugger commands from stdin until told to quit, stdin is closed or
+/// the host is closed. While continuing, frames are paced at 60 per second if
+/// `throttle` is set.
+fn run_debugger<T: Chip8System>(cpu: Cpu<T>, throttle: bool) -> Cpu<T> {
+    let mut debugger = Debugger::new(cpu);
+    let mut pacer = FramePacer::new(TIMER_FREQUENCY);
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    debugger.print_location(&mut out).unwrap();
+    loop {
+        write!(out, "(chip8) ").unwrap();
+        out.flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
         }
-    }
 
-    fn fill_from_index(&mut self, reg: u8) {
-        for n in 0..reg {
-            let val = self.mem.read(self.regs.index.wrapping_add(n as u16));
-            self.regs.set_data(n, val);
+        match debugger.execute(&line, &mut out).unwrap() {
+            Action::Prompt => {},
+            Action::Quit => break,
+            Action::Continue => {
+                let reason = loop {
+                    if let Some(reason) = debugger.run_frame() { break reason; }
+                    if throttle { pacer.wait(); }
+                };
+                debugger.report_stop(&reason, &mut out).unwrap();
+                if reason == StopReason::Closed { break; }
+            },
         }
     }
 
-    fn read_opcode(&mut self) -> (u8, u8, u8, u8) {
-        let word = self.mem.read_word(self.regs.address);
-
-        self.regs.address = self.regs.address.wrapping_add(2);
-
-        let nib1 = word & 0xF;
-        let nib2 = (word & 0xF0) >> 4;
-        let nib3 = (word & 0xF00) >> 8;
-        let nib4 = (word & 0xF000) >> 12;
-
-        (nib4 as u8, nib3 as u8, nib2 as u8, nib1 as u8)
-    }
-    
-    fn jump(&mut self, address: u16) {
-        self.regs.address = address;
-    }
-
-    fn sub(&mut self, address: u16) {
-        let return_addr = self.regs.address;
-        self.push_addr(return_addr);
-        self.regs.address = address;
-    }
-
-    fn ret(&mut self) {
-        self.regs.address = self.pop_addr();
-    }
-
-    fn push(&mut self, value: u8) {
-        self.mem.write(self.regs.stack, value);
-        self.regs.stack = self.regs.stack.wrapping_add(1);
-    }
-
-    fn push_addr(&mut self, address: u16) {
-       let x = (address & 0xFF) as u8;
-       let y = ((address >> 8) & 0xFF) as u8;
-       self.push(x);
-       self.push(y);
-    }
-
-    fn pop(&mut self) -> u8 {
-        self.regs.stack = self.regs.stack.wrapping_sub(1);
-        self.mem.read(self.regs.stack)
-    }
-
-    fn pop_addr(&mut self) -> u16 {
-        let y = self.pop() as u16;
-        let x = self.pop() as u16;
-
-        x | (y << 8)
-    }
-    
-    fn join_two(a: u8, b: u8) -> u8 {
-        let a1 = a as u8;
-        let b1 = b as u8;
-        (((a1 & 0xF) << 4) | (b1 & 0xF)) as u8
-    }
+    debugger.into_cpu()
+}
 
-    fn join_three(a: u8, b: u8, c: u8) -> u16 {
-        let a1 = a as u16;
-        let b1 = b as u16;
-        let c1 = c as u16;
-        (((a1 & 0xF) << 8) | ((b1 & 0xF) << 4) | (c1 & 0xF)) as u16
+/// Runs a frame, returning true once the host has been closed. In strict mode
+/// an invalid instruction ends the process after dumping the registers.
+fn run_frame<T: Chip8System>(cpu: &mut Cpu<T>) -> bool {
+    match cpu.run_frame() {
+        Ok(closed) => closed,
+        Err(err) => crash(cpu, &err),
     }
 }
 
-struct Display {
-    screen: [u8;2048],
+fn crash<T: Chip8System>(cpu: &Cpu<T>, err: &CpuError) -> ! {
+    eprintln!("{}", err);
+    eprintln!("{}", cpu.registers());
+    process::exit(1)
 }
 
-impl Display {
-    fn new() -> Display {
-        Display {
-            screen: [0; 2048]
-        }
-    }
-
-    fn clear_screen(&mut self) {
-        for n in 0..2048 {
-            self.screen[n] = 0;
-        }
-    }
-
-    fn toggle_pixel(&mut self, pixel: u8,  x: u8, y: u8) -> bool {
-        let real_x = (x & 0x3F) as u16;
-        let real_y = (y & 0x1F) as u16;
-        let offset = ((real_y * 64) + real_x) as usize;
-        let flipped = self.screen[offset] != 0 && pixel != 0;
-        self.screen[offset] = pixel ^ self.screen[offset];
-        flipped
-    }
-
-    fn draw_line(&mut self, line: u8, x: u8, y: u8) -> bool {
-        let mut flipped = false;
-        for n in 0..8 {
-            flipped |= self.toggle_pixel(((line << n) & 0x80) >> 7, x.wrapping_add(n), y);
-        }
-        flipped
-    }
+fn parse_profile(args: &Args) -> Profile {
+    Profile::from_name(&args.flag_profile).unwrap_or_else(|| {
+        docopt::Error::Argv(format!("Unknown profile '{}'", args.flag_profile)).exit()
+    })
 }
 
-struct Registers {
-    data: [u8;16],
-    address: u16,
-    stack: u16,
-    index: u16,
-    delay_timer: u8,
-    sound_timer: u8,
+/// Loads the bindings for the ROM being run, either from a preset or from a
+/// keymap file.
+fn load_keymap(args: &Args) -> Keymap {
+    if let Some(layout) = Layout::from_name(&args.flag_keymap) {
+        return Keymap::new(layout);
+    }
+    let config = KeymapConfig::load(&args.flag_keymap).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    let rom = Path::new(&args.arg_file).file_name().map(|name| name.to_string_lossy().into_owned());
+    config.keymap_for(&rom.unwrap_or_default())
 }
 
-impl Registers { 
-    fn new() -> Registers {
-        Registers {
-            data: [0; 16],
-            address: 0x200,
-            stack: 0xEA0,
-            index: 0,
-            delay_timer: 0,
-            sound_timer: 0,
-        }
-    }
-
-    fn get_data(&self, ind: u8) -> u8 {
-        self.data[(ind & 0xF) as usize]
-    }
-
-    fn set_data(&mut self, ind: u8, value: u8) {
-        self.data[(ind & 0xF) as usize] = value;
-    }
+/// Loads the palette named on the command line, then applies any colours
+/// given separately.
+fn load_palette(args: &Args) -> Palette {
+    let mut palette = Palette::theme(&args.flag_palette).unwrap_or_else(|| {
+        Palette::load(&args.flag_palette).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1)
+        })
+    });
+    if let Some(ref colour) = args.flag_background {
+        palette.set_colour(0, parse_colour_arg(colour));
+    }
+    if let Some(ref colour) = args.flag_foreground {
+        palette.set_foreground(parse_colour_arg(colour));
+    }
+    palette
 }
 
-struct Memory {
-    bytes: [u8;0x1000],
+fn parse_colour_arg(colour: &str) -> Colour {
+    rust_chip8::parse_colour(colour).unwrap_or_else(|| {
+        docopt::Error::Argv(format!("Expected a #RRGGBB colour, not '{}'", colour)).exit()
+    })
 }
 
-impl Memory {
-    fn new() -> Memory {
-        Memory {
-            bytes: [0; 0x1000]
-        }
+fn parse_persistence(args: &Args) -> Persistence {
+    match &args.flag_persistence[..] {
+        "off" => Persistence::Off,
+        "or" => Persistence::FrameOr,
+        "decay" if args.flag_decay >= 0.0 && args.flag_decay < 1.0 => Persistence::Decay(args.flag_decay),
+        "decay" => docopt::Error::Argv("--decay must be at least 0 and less than 1".to_string()).exit(),
+        mode => docopt::Error::Argv(format!("Unknown persistence mode '{}'", mode)).exit(),
     }
-    
-    fn new_with_rom(rom: Vec<u8>) -> Memory {
-        let mut bytes = [0; 0x1000];
-        for x in 0..rom.len() {
-            bytes[x + 0x200] = rom[x];
-        }
-
-        let font_bytes = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-            
-        ];
-
-        for x in 0..font_bytes.len() {
-            bytes[x] = font_bytes[x];
-        }
+}
 
-        Memory {
-            bytes: bytes,
-        }
-    }
+/// Loads the built in shader named on the command line, or failing that a
+/// shader file.
+fn load_shader(args: &Args) -> String {
+    if let Some(source) = chip_gl::builtin_shader(&args.flag_shader) {
+        return source.to_string();
+    }
+    let mut source = String::new();
+    File::open(&args.flag_shader).and_then(|mut f| f.read_to_string(&mut source)).unwrap_or_else(|err| {
+        eprintln!("Cannot read shader '{}': {}", args.flag_shader, err);
+        process::exit(1)
+    });
+    source
+}
 
-    fn read(&self, addr: u16) -> u8 {
-        let safe_addr = addr & 0xFFF;
-        self.bytes[safe_addr as usize]
+fn parse_scaling(args: &Args) -> Scaling {
+    match &args.flag_scaling[..] {
+        "integer" => Scaling::Integer,
+        "aspect" => Scaling::Aspect,
+        "stretch" => Scaling::Stretch,
+        mode => docopt::Error::Argv(format!("Unknown scaling mode '{}'", mode)).exit(),
     }
+}
 
-    fn read_word(&self, addr: u16) -> u16 {
-        (self.read(addr.wrapping_add(1)) as u16) | ((self.read(addr) as u16) << 8)
+/// Starts listening for game controllers, unless told not to or the
+/// platform has no support for them.
+fn open_gamepad(args: &Args) -> Option<Gamepad> {
+    if args.flag_no_gamepad {
+        return None;
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
-        let safe_addr = addr & 0xFFF;
-        self.bytes[safe_addr as usize] = value;
+    match GilrsBackend::new() {
+        Some(backend) => Some(Gamepad::new(Box::new(backend))),
+        None => {
+            eprintln!("No gamepad support available, continuing with the keyboard only");
+            None
+        }
     }
 }
 
-struct ConsoleRenderer;
-
-impl ConsoleRenderer {
-    fn new() -> ConsoleRenderer {
-        ConsoleRenderer
-    }
+fn parse_random(args: &Args) -> RandomMode {
+    RandomMode::from_name(&args.flag_random).unwrap_or_else(|| {
+        docopt::Error::Argv(format!("Unknown random number generator '{}'", args.flag_random)).exit()
+    })
 }
 
-impl Chip8System for ConsoleRenderer {
-    fn render(&mut self, screen: &[u8; 2048]) {
-        let mut s = String::from("\x1b[2J\x1b[1;1H");
-        for n in 0..2048 {
-            if n % 64 == 0 && n != 0 {
-                s.push_str("\n");
-            }
-            s.push_str(&format!("{0}", screen[n]));
+fn create_cpu<T: Chip8System>(rom: Vec<u8>, system: T, profile: Profile, args: &Args) -> Cpu<T> {
+    let mut cpu = Cpu::new_with_profile(rom, system, profile);
+    cpu.set_clock_speed(args.flag_ips);
+    if let Some(seed) = args.flag_seed {
+        cpu.set_seed(seed);
+    }
+    let seed = cpu.seed();
+    cpu.set_random_source(parse_random(args).source(seed));
+    cpu.set_strict(args.flag_strict);
+    if let Some(ref path) = args.flag_load_state {
+        let result = File::open(path)
+            .map_err(StateError::from)
+            .and_then(|mut f| cpu.load_state(&mut f));
+        if let Err(err) = result {
+            eprintln!("Cannot load state from {}: {}", path, err);
+            process::exit(1);
         }
-
-        let stdout = io::stdout();
-        let mut handle = stdout.lock();
-
-        let _ = handle.write_all(s.as_bytes());
-        let _ = handle.flush();
-
-        ::std::thread::sleep_ms(160);
-    }
-
-    fn get_input(&mut self) -> Vec<u8> {
-        Vec::new()
     }
+    cpu.tone_mut().set_pitch(args.flag_pitch);
+    cpu.tone_mut().set_volume(args.flag_volume);
+    cpu
+}
 
-    fn is_closed(&mut self) -> bool {
-        false
+/// Opens the requested audio output. The sound card is only used when
+/// `live` is set, so headless runs stay silent unless writing a WAV file.
+fn open_audio(args: &Args, live: bool) -> Option<Box<dyn AudioOutput>> {
+    if args.flag_mute {
+        return None;
+    }
+
+    match args.flag_wav {
+        Some(ref path) => {
+            let writer = WavWriter::create(path, WAV_SAMPLE_RATE).unwrap_or_else(|err| {
+                eprintln!("Cannot write sound to {}: {}", path, err);
+                process::exit(1)
+            });
+            Some(Box::new(writer))
+        },
+        None if live => {
+            match CpalOutput::new() {
+                Some(output) => Some(Box::new(output)),
+                None => {
+                    eprintln!("No audio device available, continuing without sound");
+                    None
+                }
+            }
+        },
+        None => None,
     }
 }
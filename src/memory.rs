@@ -0,0 +1,208 @@
+use std::cmp;
+use std::io::{self, Read};
+
+/// The address programs are loaded at and start executing from.
+pub const ROM_START: u16 = 0x200;
+
+/// Where the 4x5 hex font sprites start, 5 bytes per character.
+pub const FONT_ADDRESS: u16 = 0x000;
+
+/// Where the SUPER-CHIP 8x10 hex font sprites start, 10 bytes per character.
+pub const BIG_FONT_ADDRESS: u16 = 0x050;
+
+/// The size of standard CHIP-8 and SUPER-CHIP memory.
+pub const MEMORY_SIZE: usize = 0x1000;
+
+/// The size of XO-CHIP memory.
+pub const XO_CHIP_MEMORY_SIZE: usize = 0x10000;
+
+/// The largest ROM that fits between `ROM_START` and the end of XO-CHIP
+/// memory. Standard memory holds at most `MEMORY_SIZE - ROM_START` bytes.
+pub const MAX_ROM_SIZE: usize = XO_CHIP_MEMORY_SIZE - ROM_START as usize;
+
+/// Reads a ROM image, failing if it is larger than `MAX_ROM_SIZE` bytes.
+pub fn load_rom<R: Read>(source: R) -> io::Result<Vec<u8>> {
+    let mut rom = Vec::new();
+    source.take(MAX_ROM_SIZE as u64 + 1).read_to_end(&mut rom)?;
+    if rom.len() > MAX_ROM_SIZE {
+        let message = format!("ROMs can be at most {} bytes", MAX_ROM_SIZE);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(rom)
+}
+
+/// A write to a watched address, as recorded by `Memory::write`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// The address space, with the hex fonts below 0x200. This is 4 KiB, or
+/// 64 KiB for XO-CHIP programs.
+///
+/// Addresses can be watched, in which case every write to them is recorded
+/// until collected with `take_watch_hits`.
+pub struct Memory {
+    bytes: Vec<u8>,
+    watchpoints: Vec<u16>,
+    watch_hits: Vec<WatchHit>,
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            bytes: vec![0; MEMORY_SIZE],
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+        }
+    }
+
+    /// Creates 4 KiB of memory holding the fonts and `rom`. Anything in `rom`
+    /// that does not fit is dropped.
+    pub fn new_with_rom(rom: Vec<u8>) -> Memory {
+        Memory::new_with_size(rom, MEMORY_SIZE)
+    }
+
+    /// Creates 64 KiB of XO-CHIP memory holding the fonts and `rom`.
+    pub fn new_xo_chip_with_rom(rom: Vec<u8>) -> Memory {
+        Memory::new_with_size(rom, XO_CHIP_MEMORY_SIZE)
+    }
+
+    fn new_with_size(rom: Vec<u8>, size: usize) -> Memory {
+        let mut bytes = vec![0; size];
+        for x in 0..cmp::min(rom.len(), size - ROM_START as usize) {
+            bytes[x + ROM_START as usize] = rom[x];
+        }
+
+        let font_bytes = [
+            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+            0x20, 0x60, 0x20, 0x20, 0x70, // 1
+            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+            0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+
+        ];
+
+        for x in 0..font_bytes.len() {
+            bytes[x + FONT_ADDRESS as usize] = font_bytes[x];
+        }
+
+        let big_font_bytes = [
+            0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+            0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+            0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+            0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+            0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+            0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+            0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+        ];
+
+        for x in 0..big_font_bytes.len() {
+            bytes[x + BIG_FONT_ADDRESS as usize] = big_font_bytes[x];
+        }
+
+        Memory {
+            bytes,
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+        }
+    }
+
+    /// The raw contents of memory.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The number of addressable bytes. Addresses beyond this wrap around.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The address `addr` refers to once wrapped around to the size of
+    /// memory.
+    pub fn wrap(&self, addr: u16) -> u16 {
+        (addr as usize & (self.bytes.len() - 1)) as u16
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.bytes[self.wrap(addr) as usize]
+    }
+
+    /// Reads the big-endian word at `addr`.
+    pub fn read_word(&self, addr: u16) -> u16 {
+        (self.read(addr.wrapping_add(1)) as u16) | ((self.read(addr) as u16) << 8)
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        let safe_addr = self.wrap(addr) as usize;
+        if self.watchpoints.contains(&(safe_addr as u16)) {
+            self.watch_hits.push(WatchHit {
+                address: safe_addr as u16,
+                old: self.bytes[safe_addr],
+                new: value,
+            });
+        }
+        self.bytes[safe_addr] = value;
+    }
+
+    /// Replaces the contents of memory, as when loading a save state.
+    /// `bytes` must be the same size as memory. Watchpoints are kept, but not
+    /// triggered.
+    pub(crate) fn restore(&mut self, bytes: &[u8]) {
+        self.bytes.copy_from_slice(bytes);
+    }
+
+    /// Starts recording writes to `addr`.
+    pub fn add_watchpoint(&mut self, addr: u16) {
+        let safe_addr = self.wrap(addr);
+        if !self.watchpoints.contains(&safe_addr) {
+            self.watchpoints.push(safe_addr);
+        }
+    }
+
+    /// Stops recording writes to `addr`, returning false if it was not
+    /// watched.
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        let safe_addr = self.wrap(addr);
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|&watched| watched != safe_addr);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[u16] {
+        &self.watchpoints
+    }
+
+    /// Returns the writes to watched addresses since the last call.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        ::std::mem::take(&mut self.watch_hits)
+    }
+}
@@ -0,0 +1,391 @@
+use std::io::{self, BufRead, Write};
+
+use audio::AudioOutput;
+use error::CpuError;
+use keypad::Keypad;
+use quirks::Profile;
+use random::RandomMode;
+use traits::Chip8System;
+
+/// The first line of every movie file, followed by the format version.
+const MAGIC: &str = "CHIP-8 movie";
+
+/// The movie format version written by this build.
+pub const MOVIE_VERSION: u32 = 2;
+
+/// A recording of the keys on every frame of a run, along with what is
+/// needed to reproduce the run exactly: the ROM, the profile, the clock
+/// speed and the random number generator and its seed.
+///
+/// Movies are text files: a header of `name value` lines, then a `keys` line
+/// followed by one line per frame. Each holds two hex bitmasks, bit N set
+/// for key N: the keys held at the end of that frame, then the keys that
+/// went down during it, so that taps within a frame are kept. Version 1
+/// movies have only the first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub profile: Profile,
+    pub clock_speed: u32,
+    pub seed: u64,
+    pub random: RandomMode,
+    frames: Vec<(u16, u16)>,
+}
+
+impl Movie {
+    pub fn new(rom_hash: u64, profile: Profile, clock_speed: u32, seed: u64) -> Movie {
+        Movie {
+            rom_hash,
+            profile,
+            clock_speed,
+            seed,
+            random: RandomMode::XorShift,
+            frames: Vec::new(),
+        }
+    }
+
+    /// The number of frames recorded.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if no frames have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Records the next frame's keys as bitmasks: those held at its end
+    /// and those that went down during it, as `Keypad::replay` takes them.
+    pub fn push_frame(&mut self, held: u16, presses: u16) {
+        self.frames.push((held, presses));
+    }
+
+    /// The keys held at the end of `frame` and those pressed during it, or
+    /// `None` past the end of the movie.
+    pub fn keys(&self, frame: usize) -> Option<(u16, u16)> {
+        self.frames.get(frame).cloned()
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{} {}", MAGIC, MOVIE_VERSION)?;
+        writeln!(out, "rom {:016x}", self.rom_hash)?;
+        writeln!(out, "profile {}", self.profile.name())?;
+        writeln!(out, "ips {}", self.clock_speed)?;
+        writeln!(out, "seed {}", self.seed)?;
+        writeln!(out, "random {}", self.random.name())?;
+        writeln!(out, "keys")?;
+        for &(held, presses) in self.frames.iter() {
+            writeln!(out, "{:04x} {:04x}", held, presses)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: R) -> io::Result<Movie> {
+        let mut lines = input.lines();
+        let version = match lines.next() {
+            Some(line) => {
+                let line = line?;
+                if !line.starts_with(MAGIC) {
+                    return Err(invalid("not a movie file".to_string()));
+                }
+                line[MAGIC.len()..].trim().parse::<u32>().map_err(|_| invalid("bad version".to_string()))?
+            },
+            None => return Err(invalid("not a movie file".to_string())),
+        };
+        if version > MOVIE_VERSION {
+            return Err(invalid(format!("movie version {} is newer than this build supports", version)));
+        }
+
+        let mut movie = Movie::new(0, Profile::SuperChip, 0, 0);
+        let mut in_keys = false;
+        for (n, line) in lines.enumerate() {
+            let line = line?;
+            let line = line.trim();
+            let bad_line = || invalid(format!("bad line {}: '{}'", n + 2, line));
+            if in_keys {
+                let masks = line.split_whitespace()
+                    .map(|mask| u16::from_str_radix(mask, 16).map_err(|_| bad_line()))
+                    .collect::<Result<Vec<u16>, io::Error>>()?;
+                match (masks.len(), version) {
+                    (1, 1) => movie.frames.push((masks[0], 0)),
+                    (2, _) => movie.frames.push((masks[0], masks[1])),
+                    _ => return Err(bad_line()),
+                }
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("rom"), Some(hash)) => movie.rom_hash = u64::from_str_radix(hash, 16).map_err(|_| bad_line())?,
+                (Some("profile"), Some(name)) => movie.profile = Profile::from_name(name).ok_or_else(&bad_line)?,
+                (Some("ips"), Some(ips)) => movie.clock_speed = ips.parse().map_err(|_| bad_line())?,
+                (Some("seed"), Some(seed)) => movie.seed = seed.parse().map_err(|_| bad_line())?,
+                (Some("random"), Some(name)) => movie.random = RandomMode::from_name(name).ok_or_else(&bad_line)?,
+                (Some("keys"), None) => in_keys = true,
+                _ => return Err(bad_line()),
+            }
+        }
+        Ok(movie)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Wraps a host, recording its keys into a `Movie`.
+///
+/// Each frame the host's key events are gathered on a copy of the keypad,
+/// and the keys held and pressed by them are recorded, then replayed onto
+/// the machine's keypad just as `MoviePlayer` will, so the machine sees
+/// exactly what the movie holds, taps within a frame included.
+pub struct MovieRecorder<S: Chip8System> {
+    inner: S,
+    movie: Movie,
+}
+
+impl<S: Chip8System> MovieRecorder<S> {
+    /// Records into `movie`, which should describe the `Cpu` the recorder
+    /// is given to.
+    pub fn new(inner: S, movie: Movie) -> MovieRecorder<S> {
+        MovieRecorder { inner, movie }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The movie recorded so far.
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn movie_mut(&mut self) -> &mut Movie {
+        &mut self.movie
+    }
+}
+
+impl<S: Chip8System> Chip8System for MovieRecorder<S> {
+    fn render(&mut self, screen: &[u8], width: usize, height: usize) {
+        self.inner.render(screen, width, height);
+    }
+
+    fn end_frame(&mut self) {
+        self.inner.end_frame();
+    }
+
+    fn update_keypad(&mut self, keypad: &mut Keypad) {
+        let mut events = keypad.clone();
+        events.clear_presses();
+        self.inner.update_keypad(&mut events);
+        self.movie.push_frame(events.held(), events.presses());
+        keypad.replay(events.held(), events.presses());
+    }
+
+    fn is_closed(&mut self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn audio_output(&mut self) -> Option<&mut dyn AudioOutput> {
+        self.inner.audio_output()
+    }
+
+    fn report_error(&mut self, error: &CpuError) {
+        self.inner.report_error(error);
+    }
+}
+
+/// Wraps a host, replacing its keys with those recorded in a `Movie`. The
+/// host is closed once the movie runs out.
+pub struct MoviePlayer<S: Chip8System> {
+    inner: S,
+    movie: Movie,
+    frame: usize,
+}
+
+impl<S: Chip8System> MoviePlayer<S> {
+    pub fn new(inner: S, movie: Movie) -> MoviePlayer<S> {
+        MoviePlayer { inner, movie, frame: 0 }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// The frames played so far.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+}
+
+impl<S: Chip8System> Chip8System for MoviePlayer<S> {
+    fn render(&mut self, screen: &[u8], width: usize, height: usize) {
+        self.inner.render(screen, width, height);
+    }
+
+    fn end_frame(&mut self) {
+        self.inner.end_frame();
+    }
+
+    fn update_keypad(&mut self, keypad: &mut Keypad) {
+        if let Some((held, presses)) = self.movie.keys(self.frame) {
+            keypad.replay(held, presses);
+        }
+        self.frame += 1;
+    }
+
+    fn is_closed(&mut self) -> bool {
+        self.inner.is_closed() || self.frame >= self.movie.len()
+    }
+
+    fn audio_output(&mut self) -> Option<&mut dyn AudioOutput> {
+        self.inner.audio_output()
+    }
+
+    fn report_error(&mut self, error: &CpuError) {
+        self.inner.report_error(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+    use headless::HeadlessSystem;
+    use state::rom_hash;
+
+    /// Holds a scripted sequence of keys, one entry per frame.
+    struct ScriptedSystem {
+        script: Vec<u16>,
+    }
+
+    impl Chip8System for ScriptedSystem {
+        fn render(&mut self, _: &[u8], _: usize, _: usize) {}
+
+        fn update_keypad(&mut self, keypad: &mut Keypad) {
+            keypad.set_held(if self.script.is_empty() { 0 } else { self.script.remove(0) });
+        }
+
+        fn is_closed(&mut self) -> bool {
+            false
+        }
+    }
+
+    /// Taps key 3 within every frame.
+    struct TappingSystem;
+
+    impl Chip8System for TappingSystem {
+        fn render(&mut self, _: &[u8], _: usize, _: usize) {}
+
+        fn update_keypad(&mut self, keypad: &mut Keypad) {
+            keypad.press(3);
+            keypad.release(3);
+        }
+
+        fn is_closed(&mut self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn recorder_forwards_events() {
+        let movie = Movie::new(0, Profile::SuperChip, 60, 0);
+        let mut cpu = Cpu::new(vec![0x12, 0x00], MovieRecorder::new(TappingSystem, movie));
+        cpu.run_frame().unwrap();
+        assert_eq!(cpu.keypad_mut().take_press(), Some(3), "the machine still sees the tap");
+        assert_eq!(cpu.system().movie().keys(0), Some((0, 0x0008)));
+    }
+
+    #[test]
+    fn replays_taps() {
+        // Adds 1 to V1 for every key FX0A sees.
+        let rom = vec![0xF0, 0x0A, 0x71, 0x01, 0x12, 0x00];
+        let movie = Movie::new(rom_hash(&rom), Profile::SuperChip, 600, 0);
+        let mut cpu = Cpu::new(rom.clone(), MovieRecorder::new(TappingSystem, movie));
+        cpu.set_clock_speed(600);
+        for _ in 0..4 {
+            cpu.run_frame().unwrap();
+        }
+        assert!(cpu.registers().data[1] > 0);
+        let movie = cpu.system().movie().clone();
+
+        let mut replay = Cpu::new(rom, MoviePlayer::new(HeadlessSystem::new(), movie));
+        replay.set_clock_speed(600);
+        while !replay.run_frame().unwrap() {}
+        assert_eq!(replay.registers().data, cpu.registers().data);
+        assert_eq!(replay.registers().address, cpu.registers().address);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut movie = Movie::new(0xDEADBEEF, Profile::XoChip, 1000, 42);
+        movie.random = RandomMode::Vip;
+        movie.push_frame(0, 0);
+        movie.push_frame(0x8002, 0x0003);
+        let mut text = Vec::new();
+        movie.write(&mut text).unwrap();
+        assert_eq!(String::from_utf8(text.clone()).unwrap(), "\
+CHIP-8 movie 2
+rom 00000000deadbeef
+profile xo-chip
+ips 1000
+seed 42
+random vip
+keys
+0000 0000
+8002 0003
+");
+        assert_eq!(Movie::read(&text[..]).unwrap(), movie);
+        assert_eq!(movie.keys(1), Some((0x8002, 0x0003)));
+        assert_eq!(movie.keys(2), None);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(Movie::read(&b"hello"[..]).is_err());
+        assert!(Movie::read(&b"CHIP-8 movie 99\n"[..]).is_err());
+        assert!(Movie::read(&b"CHIP-8 movie 1\nprofile nope\n"[..]).is_err());
+        assert!(Movie::read(&b"CHIP-8 movie 1\nkeys\nxyz\n"[..]).is_err());
+        assert!(Movie::read(&b"CHIP-8 movie 2\nkeys\n0001\n"[..]).is_err(), "version 2 records presses");
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let movie = Movie::read(&b"CHIP-8 movie 1\nprofile vip\nkeys\n0000\n0010\n"[..]).unwrap();
+        assert_eq!(movie.keys(1), Some((0x0010, 0)));
+    }
+
+    #[test]
+    fn replays_exactly() {
+        // Adds a random number to V1 on each press of key 5, drawing V1.
+        let rom = vec![0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0xC2, 0xFF, 0x81, 0x24, 0xF1, 0x29,
+                       0xD0, 0x05, 0xE0, 0xA1, 0x12, 0x0E, 0x12, 0x02];
+        let movie = Movie::new(rom_hash(&rom), Profile::SuperChip, 120, 7);
+        let script = (0..40).map(|n| if n % 6 < 3 { 0x20 } else { 0 }).collect();
+        let recorder = MovieRecorder::new(ScriptedSystem { script }, movie);
+        let mut cpu = Cpu::new(rom.clone(), recorder);
+        cpu.set_clock_speed(120);
+        cpu.set_seed(7);
+        for _ in 0..40 {
+            cpu.run_frame().unwrap();
+        }
+        let movie = cpu.system().movie().clone();
+        assert_eq!(movie.len(), 40);
+
+        let mut replay = Cpu::new(rom, MoviePlayer::new(HeadlessSystem::new(), movie.clone()));
+        replay.set_clock_speed(movie.clock_speed);
+        replay.set_seed(movie.seed);
+        while !replay.run_frame().unwrap() {}
+        assert_eq!(replay.system().frame(), 40);
+        assert_eq!(replay.registers().data, cpu.registers().data);
+        assert_eq!(replay.display().screen(), cpu.display().screen());
+    }
+}
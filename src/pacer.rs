@@ -0,0 +1,33 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frames the pacer may fall behind by before it gives up catching up.
+const MAX_LAG_FRAMES: u32 = 4;
+
+/// Keeps emulated frames in step with the wall clock.
+pub struct FramePacer {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl FramePacer {
+    pub fn new(frames_per_second: u32) -> FramePacer {
+        FramePacer {
+            frame_duration: Duration::new(0, 1_000_000_000 / frames_per_second),
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// Sleeps until the next frame is due. If the host has fallen too far
+    /// behind, the schedule is reset rather than running frames back to back
+    /// to catch up.
+    pub fn wait(&mut self) {
+        self.next_frame += self.frame_duration;
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_duration * MAX_LAG_FRAMES {
+            self.next_frame = now;
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use config::{self, ConfigError, ConfigFile, Entry};
+
+/// An RGB colour.
+pub type Colour = [u8; 3];
+
+/// The colours pixels are shown in, indexed by the bitmask of planes a pixel
+/// is lit on: the background, plane 1 (the only plane outside XO-CHIP),
+/// plane 2, and both planes at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    colours: [Colour; 4],
+}
+
+/// The built in themes, by name.
+const THEMES: [(&str, [Colour; 4]); 5] = [
+    ("classic", [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55]]),
+    ("green", [[0x0A, 0x14, 0x0A], [0x33, 0xFF, 0x66], [0x1F, 0x99, 0x3D], [0x99, 0xFF, 0xB3]]),
+    ("amber", [[0x14, 0x0C, 0x00], [0xFF, 0xB0, 0x00], [0x99, 0x6A, 0x00], [0xFF, 0xD8, 0x80]]),
+    ("lcd", [[0x9B, 0xBC, 0x0F], [0x0F, 0x38, 0x0F], [0x30, 0x62, 0x30], [0x8B, 0xAC, 0x0F]]),
+    ("octo", [[0x99, 0x66, 0x00], [0xFF, 0xCC, 0x00], [0xFF, 0x66, 0x00], [0x66, 0x22, 0x00]]),
+];
+
+impl Palette {
+    /// A two colour palette. The XO-CHIP colours are shades between the
+    /// two, as in the classic theme.
+    pub fn new(background: Colour, foreground: Colour) -> Palette {
+        let mut palette = Palette { colours: [background; 4] };
+        palette.set_foreground(foreground);
+        palette
+    }
+
+    /// The built in theme called `name`: classic, green, amber, lcd or
+    /// octo.
+    pub fn theme(name: &str) -> Option<Palette> {
+        THEMES.iter().find(|&&(theme, _)| theme == name).map(|&(_, colours)| Palette { colours })
+    }
+
+    /// Loads a palette file. Like keymaps, these are a small subset of TOML:
+    ///
+    /// ```toml
+    /// theme = "amber"        # start from a built in theme
+    /// background = "#000000"
+    /// foreground = "#FFB000"
+    /// plane2 = "#996A00"     # XO-CHIP's second plane
+    /// overlap = "#FFD880"    # both planes
+    /// ```
+    ///
+    /// Setting the foreground shades the XO-CHIP colours between it and the
+    /// background, unless they are given too.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, ConfigError> {
+        Palette::from_file(&ConfigFile::load(path.as_ref())?)
+    }
+
+    pub fn parse(text: &str) -> Result<Palette, ConfigError> {
+        Palette::from_file(&ConfigFile::parse("<input>", text)?)
+    }
+
+    fn from_file(file: &ConfigFile) -> Result<Palette, ConfigError> {
+        let mut palette = Palette::default();
+        let mut colours: [Option<Colour>; 4] = [None; 4];
+        for &(line, ref entry) in file.entries() {
+            let (key, value) = match *entry {
+                Entry::Section(_) => return Err(file.error(line, "Palettes have no sections")),
+                Entry::Setting(ref key, ref value) => (key, value),
+            };
+            let value = config::parse_string(value).ok_or_else(|| file.error(line, "Expected a quoted value"))?;
+            if key == "theme" {
+                palette = Palette::theme(&value).ok_or_else(|| file.error(line, format!("Unknown theme '{}'", value)))?;
+                continue;
+            }
+
+            let index = match &key[..] {
+                "background" => 0,
+                "foreground" => 1,
+                "plane2" => 2,
+                "overlap" => 3,
+                _ => return Err(file.error(line, format!("Unknown setting '{}'", key))),
+            };
+            colours[index] = Some(parse_colour(&value)
+                .ok_or_else(|| file.error(line, format!("Expected a #RRGGBB colour, not '{}'", value)))?);
+        }
+
+        if let Some(background) = colours[0] {
+            palette.colours[0] = background;
+        }
+        if let Some(foreground) = colours[1] {
+            palette.set_foreground(foreground);
+        }
+        for (pixel, colour) in colours.iter().enumerate().skip(2) {
+            if let Some(colour) = *colour {
+                palette.colours[pixel] = colour;
+            }
+        }
+        Ok(palette)
+    }
+
+    /// The colour of a pixel lit on the planes in the bitmask `pixel`.
+    pub fn colour(&self, pixel: u8) -> Colour {
+        self.colours[(pixel & 0x3) as usize]
+    }
+
+    /// Sets the colour of pixels lit on the planes in the bitmask `pixel`.
+    pub fn set_colour(&mut self, pixel: u8, colour: Colour) {
+        self.colours[(pixel & 0x3) as usize] = colour;
+    }
+
+    /// Sets the foreground, shading the XO-CHIP colours between it and the
+    /// background: two thirds of the way for plane 2 and one third for
+    /// both planes.
+    pub fn set_foreground(&mut self, foreground: Colour) {
+        let background = self.colours[0];
+        let shade = |thirds: u32| {
+            let mut colour = [0; 3];
+            for i in 0..3 {
+                let (from, to) = (background[i] as u32, foreground[i] as u32);
+                colour[i] = ((from * (3 - thirds) + to * thirds) / 3) as u8;
+            }
+            colour
+        };
+        self.colours[1] = foreground;
+        self.colours[2] = shade(2);
+        self.colours[3] = shade(1);
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette { colours: THEMES[0].1 }
+    }
+}
+
+/// Parses a `#RRGGBB` colour. The `#` is optional.
+pub fn parse_colour(text: &str) -> Option<Colour> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Some([channel(0), channel(2), channel(4)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn themes() {
+        assert_eq!(Palette::theme("classic"), Some(Palette::default()));
+        assert_eq!(Palette::theme("amber").unwrap().colour(1), [0xFF, 0xB0, 0x00]);
+        assert_eq!(Palette::theme("neon"), None);
+    }
+
+    #[test]
+    fn shades_xo_chip_colours() {
+        let palette = Palette::new([0, 0, 0], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(palette, Palette::default());
+        assert_eq!(palette.colour(0x6), [0xAA, 0xAA, 0xAA], "only the low two planes count");
+    }
+
+    #[test]
+    fn parses_colours() {
+        assert_eq!(parse_colour("#33ff66"), Some([0x33, 0xFF, 0x66]));
+        assert_eq!(parse_colour("000000"), Some([0, 0, 0]));
+        assert_eq!(parse_colour("#fff"), None);
+        assert_eq!(parse_colour("#gggggg"), None);
+    }
+
+    #[test]
+    fn loads_files() {
+        let palette = Palette::parse(r##"
+theme = "green"
+background = "#000000"    # darker
+"##).unwrap();
+        assert_eq!(palette.colour(0), [0, 0, 0]);
+        assert_eq!(palette.colour(3), Palette::theme("green").unwrap().colour(3), "only a new foreground reshades");
+
+        let palette = Palette::parse("foreground = \"#FF0000\"\nplane2 = \"#00FF00\"").unwrap();
+        assert_eq!(palette.colour(2), [0, 0xFF, 0]);
+        assert_eq!(palette.colour(3), [0x55, 0, 0]);
+
+        assert_eq!(Palette::parse("theme = \"neon\"").unwrap_err().message, "Unknown theme 'neon'");
+        assert_eq!(Palette::parse("\n\nforeground = \"red\"").unwrap_err().line, 3);
+        assert!(Palette::parse("[colours]").is_err());
+    }
+}
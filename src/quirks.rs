@@ -0,0 +1,97 @@
+/// Behaviour that differs between CHIP-8 interpreters for the same opcode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY into VX, rather than shifting VX in place.
+    pub shift_uses_vy: bool,
+    /// FX55 and FX65 leave I pointing just past the last register stored or
+    /// loaded, rather than leaving it unchanged.
+    pub load_store_increments_index: bool,
+    /// Along with `load_store_increments_index`, I is advanced by X rather
+    /// than X + 1, leaving it on the last register stored or loaded.
+    pub load_store_increments_index_by_x: bool,
+    /// BNNN jumps to NNN plus VX, X being the top nibble of NNN, rather than
+    /// NNN plus V0.
+    pub jump_uses_vx: bool,
+    /// Sprites are cut off at the edges of the screen rather than wrapping
+    /// around to the other side.
+    pub clip_sprites: bool,
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0.
+    pub logic_resets_vf: bool,
+}
+
+/// The interpreters programs were commonly written against.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// The original interpreter on the RCA COSMAC VIP.
+    CosmacVip,
+    /// CHIP-48 on the HP-48 calculators. Its quirks are SUPER-CHIP's, but for
+    /// FX55 and FX65 advancing I by X.
+    Chip48,
+    /// SUPER-CHIP 1.1, the default.
+    SuperChip,
+    /// XO-CHIP, which also enables its extra instructions and 64 KiB of memory.
+    XoChip,
+}
+
+/// The profile used unless told otherwise.
+pub const DEFAULT_PROFILE: Profile = Profile::SuperChip;
+
+impl Profile {
+    /// Looks up a profile by the name used on the command line: `vip`,
+    /// `chip-48`, `schip` or `xo-chip`.
+    pub fn from_name(name: &str) -> Option<Profile> {
+        match name {
+            "vip" => Some(Profile::CosmacVip),
+            "chip-48" => Some(Profile::Chip48),
+            "schip" => Some(Profile::SuperChip),
+            "xo-chip" => Some(Profile::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Profile::CosmacVip => "vip",
+            Profile::Chip48 => "chip-48",
+            Profile::SuperChip => "schip",
+            Profile::XoChip => "xo-chip",
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match *self {
+            Profile::CosmacVip => Quirks {
+                shift_uses_vy: true,
+                load_store_increments_index: true,
+                load_store_increments_index_by_x: false,
+                jump_uses_vx: false,
+                clip_sprites: true,
+                logic_resets_vf: true,
+            },
+            Profile::Chip48 => Quirks {
+                shift_uses_vy: false,
+                load_store_increments_index: true,
+                load_store_increments_index_by_x: true,
+                jump_uses_vx: true,
+                clip_sprites: true,
+                logic_resets_vf: false,
+            },
+            Profile::SuperChip => Quirks {
+                shift_uses_vy: false,
+                load_store_increments_index: false,
+                load_store_increments_index_by_x: false,
+                jump_uses_vx: true,
+                clip_sprites: true,
+                logic_resets_vf: false,
+            },
+            Profile::XoChip => Quirks {
+                shift_uses_vy: true,
+                load_store_increments_index: true,
+                load_store_increments_index_by_x: false,
+                jump_uses_vx: false,
+                clip_sprites: false,
+                logic_resets_vf: false,
+            },
+        }
+    }
+}
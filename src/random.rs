@@ -0,0 +1,226 @@
+
+use memory::Memory;
+
+/// Where CXNN gets its random bytes from.
+pub trait RandomSource {
+    /// The generator's name, as `RandomMode::name` gives it. Save states
+    /// record it, so that they are only restored into the same generator.
+    fn name(&self) -> &'static str;
+
+    /// Restarts the sequence, so that the same seed always produces the same
+    /// bytes.
+    fn reseed(&mut self, seed: u64);
+
+    /// The next random byte. The machine's memory is passed in for
+    /// generators that, like the VIP's, mix in memory contents.
+    fn next_byte(&mut self, memory: &Memory) -> u8;
+
+    /// Called at the end of every frame.
+    fn end_frame(&mut self) {}
+
+    /// The generator's internal state, for save states.
+    fn state(&self) -> Vec<u8>;
+
+    /// Restores a state returned by `state`. Returns false, leaving the
+    /// generator as it was, if `state` is not one of this generator's.
+    fn set_state(&mut self, state: &[u8]) -> bool;
+}
+
+/// A fast, good quality generator. This is the default.
+///
+/// This is Marsaglia's xorshift128, producing the same numbers as `rand`'s
+/// `XorShiftRng`, but with its state open to save states.
+pub struct XorShiftSource {
+    words: [u32; 4],
+}
+
+impl XorShiftSource {
+    pub fn new(seed: u64) -> XorShiftSource {
+        let mut source = XorShiftSource { words: [0; 4] };
+        source.reseed(seed);
+        source
+    }
+}
+
+impl RandomSource for XorShiftSource {
+    fn name(&self) -> &'static str {
+        RandomMode::XorShift.name()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        // XorShift needs a non-zero seed, so the upper words are fixed.
+        self.words = [seed as u32, (seed >> 32) as u32, 0x9E3779B9, 0x7F4A7C15];
+    }
+
+    fn next_byte(&mut self, _: &Memory) -> u8 {
+        let [x, y, z, w] = self.words;
+        let t = x ^ (x << 11);
+        let next = w ^ (w >> 19) ^ t ^ (t >> 8);
+        self.words = [y, z, w, next];
+        next as u8
+    }
+
+    fn state(&self) -> Vec<u8> {
+        self.words.iter().flat_map(|word| (0..4).map(move |i| (word >> (i * 8)) as u8)).collect()
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 16 {
+            return false;
+        }
+        for (word, bytes) in self.words.iter_mut().zip(state.chunks(4)) {
+            *word = bytes.iter().rev().fold(0, |word, &byte| word << 8 | byte as u32);
+        }
+        true
+    }
+}
+
+/// The COSMAC VIP interpreter's routine. A counter, stepped on every call
+/// and by every display interrupt, indexes a page of the interpreter's own
+/// code. The byte found there is added to the previous result, which is
+/// then rotated right.
+///
+/// The interpreter's code is not part of this emulator, so the page holding
+/// the fonts is read instead. The numbers are just as poor as the original's,
+/// which some games were tuned around.
+pub struct VipRandom {
+    counter: u8,
+    last: u8,
+}
+
+impl VipRandom {
+    pub fn new(seed: u64) -> VipRandom {
+        let mut source = VipRandom { counter: 0, last: 0 };
+        source.reseed(seed);
+        source
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn name(&self) -> &'static str {
+        RandomMode::Vip.name()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.counter = seed as u8;
+        self.last = (seed >> 8) as u8;
+    }
+
+    fn next_byte(&mut self, memory: &Memory) -> u8 {
+        self.counter = self.counter.wrapping_add(1);
+        let sum = self.last.wrapping_add(memory.read(self.counter as u16));
+        self.last = sum.rotate_right(1);
+        self.last
+    }
+
+    fn end_frame(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
+    }
+
+    fn state(&self) -> Vec<u8> {
+        vec![self.counter, self.last]
+    }
+
+    fn set_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 2 {
+            return false;
+        }
+        self.counter = state[0];
+        self.last = state[1];
+        true
+    }
+}
+
+/// The built in random number generators, selectable by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RandomMode {
+    XorShift,
+    Vip,
+}
+
+impl RandomMode {
+    pub fn from_name(name: &str) -> Option<RandomMode> {
+        match name {
+            "xorshift" => Some(RandomMode::XorShift),
+            "vip" => Some(RandomMode::Vip),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RandomMode::XorShift => "xorshift",
+            RandomMode::Vip => "vip",
+        }
+    }
+
+    /// Creates the generator, seeded with `seed`.
+    pub fn source(&self, seed: u64) -> Box<dyn RandomSource> {
+        match *self {
+            RandomMode::XorShift => Box::new(XorShiftSource::new(seed)),
+            RandomMode::Vip => Box::new(VipRandom::new(seed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reseeding_repeats() {
+        let memory = Memory::new_with_rom(vec![]);
+        for &mode in [RandomMode::XorShift, RandomMode::Vip].iter() {
+            let mut source = mode.source(99);
+            let first: Vec<u8> = (0..16).map(|_| source.next_byte(&memory)).collect();
+            source.reseed(99);
+            let second: Vec<u8> = (0..16).map(|_| source.next_byte(&memory)).collect();
+            assert_eq!(first, second, "{}", mode.name());
+        }
+    }
+
+    #[test]
+    fn xorshift_matches_rand() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+
+        let memory = Memory::new_with_rom(vec![]);
+        let mut source = XorShiftSource::new(0x0123456789ABCDEF);
+        let mut rng = XorShiftRng::from_seed([0x89ABCDEF, 0x01234567, 0x9E3779B9, 0x7F4A7C15]);
+        for _ in 0..64 {
+            assert_eq!(source.next_byte(&memory), rng.gen::<u8>());
+        }
+    }
+
+    #[test]
+    fn restores_state() {
+        let memory = Memory::new_with_rom(vec![]);
+        for &mode in [RandomMode::XorShift, RandomMode::Vip].iter() {
+            let mut source = mode.source(5);
+            source.next_byte(&memory);
+            let state = source.state();
+            let first: Vec<u8> = (0..16).map(|_| source.next_byte(&memory)).collect();
+            assert!(source.set_state(&state));
+            let second: Vec<u8> = (0..16).map(|_| source.next_byte(&memory)).collect();
+            assert_eq!(first, second, "{}", mode.name());
+            assert!(!source.set_state(&[0; 3]));
+        }
+    }
+
+    #[test]
+    fn vip_reads_memory() {
+        let mut memory = Memory::new_with_rom(vec![]);
+        let mut source = VipRandom::new(0);
+        memory.write(0x001, 0x10);
+        assert_eq!(source.next_byte(&memory), 0x08);
+        source.end_frame();
+        memory.write(0x003, 0x02);
+        assert_eq!(source.next_byte(&memory), 0x05);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(RandomMode::from_name("vip"), Some(RandomMode::Vip));
+        assert_eq!(RandomMode::from_name("xorshift").map(|mode| mode.name()), Some("xorshift"));
+        assert_eq!(RandomMode::from_name("dice"), None);
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt;
+
+use memory::ROM_START;
+
+/// How many return addresses the stack holds. Calls nested deeper than this
+/// overwrite the outermost ones.
+pub const STACK_SIZE: usize = 16;
+
+/// The CHIP-8 register file.
+pub struct Registers {
+    /// V0 to VF.
+    pub data: [u8;16],
+    /// The program counter.
+    pub address: u16,
+    /// The stack pointer, counting the return addresses pushed.
+    pub stack: u16,
+    /// The return addresses, kept out of `Memory` so that calls can't
+    /// overwrite the program. The nth is at `n % STACK_SIZE`.
+    pub call_stack: [u16; STACK_SIZE],
+    /// The I register.
+    pub index: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
+
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            data: [0; 16],
+            address: ROM_START,
+            stack: 0,
+            call_stack: [0; STACK_SIZE],
+            index: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+        }
+    }
+
+    pub fn get_data(&self, ind: u8) -> u8 {
+        self.data[(ind & 0xF) as usize]
+    }
+
+    pub fn set_data(&mut self, ind: u8, value: u8) {
+        self.data[(ind & 0xF) as usize] = value;
+    }
+}
+
+/// Dumps the registers over three lines: the program counter, I, the stack
+/// pointer and timers, then V0 to V7 and V8 to VF.
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "PC={:03X} I={:03X} SP={:X} DT={:02X} ST={:02X}",
+                 self.address, self.index, self.stack, self.delay_timer, self.sound_timer)?;
+        for row in 0..2 {
+            let regs: Vec<String> = (0..8)
+                .map(|n| row * 8 + n)
+                .map(|n| format!("V{:X}={:02X}", n, self.data[n]))
+                .collect();
+            write!(f, "{}", regs.join(" "))?;
+            if row == 0 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use cpu::{Cpu, TIMER_FREQUENCY};
+use traits::Chip8System;
+
+/// The difference between two save states, stored as runs of XORed bytes
+/// separated by unchanged stretches.
+struct Delta {
+    /// The length of the older state.
+    len: usize,
+    /// `(offset, bytes)` pairs, `bytes` being the XOR of the two states
+    /// from `offset` on.
+    runs: Vec<(usize, Vec<u8>)>,
+}
+
+impl Delta {
+    /// The difference that turns `from` into `to`.
+    fn between(from: &[u8], to: &[u8]) -> Delta {
+        let len = from.len().max(to.len());
+        let byte = |state: &[u8], n: usize| state.get(n).cloned().unwrap_or(0);
+
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        for n in 0..len {
+            let diff = byte(from, n) ^ byte(to, n);
+            if diff == 0 {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(&mut (offset, ref mut bytes)) if offset + bytes.len() == n => bytes.push(diff),
+                _ => runs.push((n, vec![diff])),
+            }
+        }
+        Delta { len: to.len(), runs }
+    }
+
+    fn apply(&self, state: &mut Vec<u8>) {
+        let len = state.len().max(self.len);
+        state.resize(len, 0);
+        for &(offset, ref bytes) in self.runs.iter() {
+            for (n, diff) in bytes.iter().enumerate() {
+                state[offset + n] ^= diff;
+            }
+        }
+        state.truncate(self.len);
+    }
+
+    fn size(&self) -> usize {
+        self.runs.iter().map(|(_, bytes)| bytes.len()).sum()
+    }
+}
+
+/// A ring buffer of the machine's state at the end of each recent frame,
+/// for stepping backwards in time.
+///
+/// Only the newest state is kept whole. Older ones are stored as the
+/// difference from the state after them, so a frame that changes a handful
+/// of bytes costs a handful of bytes, rather than a copy of memory.
+pub struct Rewinder {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+impl Rewinder {
+    /// Creates a buffer holding the last `seconds` of frames.
+    pub fn new(seconds: u32) -> Rewinder {
+        Rewinder {
+            capacity: (seconds * TIMER_FREQUENCY) as usize,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// The number of frames that can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Returns true if there are no frames to step back to.
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// The approximate number of bytes of state held.
+    pub fn size(&self) -> usize {
+        self.newest.as_ref().map(|state| state.len()).unwrap_or(0) +
+            self.deltas.iter().map(|delta| delta.size()).sum::<usize>()
+    }
+
+    /// Records the machine's current state, to be called once per frame.
+    /// The oldest frame is forgotten once the buffer is full.
+    pub fn record<T: Chip8System>(&mut self, cpu: &Cpu<T>) {
+        let mut state = Vec::new();
+        cpu.save_state(&mut state).expect("writing to a Vec cannot fail");
+
+        if let Some(previous) = self.newest.take() {
+            self.deltas.push_back(Delta::between(&state, &previous));
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    /// Puts the machine back to the frame before the newest one recorded,
+    /// which is forgotten. Returns false if there is nothing to go back to.
+    pub fn rewind<T: Chip8System>(&mut self, cpu: &mut Cpu<T>) -> bool {
+        let (delta, state) = match (self.deltas.pop_back(), self.newest.as_mut()) {
+            (Some(delta), Some(state)) => (delta, state),
+            _ => return false,
+        };
+        delta.apply(state);
+        cpu.load_state(&mut &state[..]).expect("a recorded state is always valid");
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headless::HeadlessSystem;
+
+    fn cpu() -> Cpu<HeadlessSystem> {
+        // Counts frames in V0 and draws with V0 as the x coordinate, then
+        // waits for the delay timer.
+        let rom = vec![0x60, 0x00, 0xA2, 0x10, 0xD0, 0x11, 0x70, 0x01, 0x61, 0x01, 0xF1, 0x15,
+                       0xF1, 0x07, 0x31, 0x00, 0x12, 0x0C, 0x12, 0x04];
+        let mut cpu = Cpu::new(rom, HeadlessSystem::new());
+        cpu.set_clock_speed(600);
+        cpu
+    }
+
+    #[test]
+    fn steps_back_frame_by_frame() {
+        let mut cpu = cpu();
+        let mut rewinder = Rewinder::new(10);
+        let mut states = Vec::new();
+        for _ in 0..20 {
+            cpu.run_frame().unwrap();
+            rewinder.record(&cpu);
+            let mut state = Vec::new();
+            cpu.save_state(&mut state).unwrap();
+            states.push(state);
+        }
+
+        assert_eq!(rewinder.len(), 19);
+        for expected in states.iter().rev().skip(1) {
+            assert!(rewinder.rewind(&mut cpu));
+            let mut state = Vec::new();
+            cpu.save_state(&mut state).unwrap();
+            assert_eq!(&state, expected);
+        }
+        assert!(!rewinder.rewind(&mut cpu));
+        assert_eq!(cpu.frames(), 1);
+    }
+
+    #[test]
+    fn is_bounded() {
+        let mut cpu = cpu();
+        let mut rewinder = Rewinder::new(1);
+        for _ in 0..200 {
+            cpu.run_frame().unwrap();
+            rewinder.record(&cpu);
+        }
+        assert_eq!(rewinder.len(), 60);
+        assert!(rewinder.size() < 2 * cpu.memory().size() + 60 * 64);
+    }
+
+    #[test]
+    fn records_after_rewinding() {
+        let mut cpu = cpu();
+        let mut rewinder = Rewinder::new(10);
+        for _ in 0..5 {
+            cpu.run_frame().unwrap();
+            rewinder.record(&cpu);
+        }
+        rewinder.rewind(&mut cpu);
+        rewinder.rewind(&mut cpu);
+        assert_eq!(cpu.frames(), 3);
+        cpu.run_frame().unwrap();
+        rewinder.record(&cpu);
+        assert_eq!(rewinder.len(), 3);
+        rewinder.rewind(&mut cpu);
+        assert_eq!(cpu.frames(), 3);
+    }
+}
@@ -0,0 +1,27 @@
+#version 140
+
+// Lets lit pixels glow onto their neighbours.
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+uniform sampler2D tex;
+uniform vec2 source_size;
+
+// How bright the glow is next to the pixel itself.
+const float STRENGTH = 0.6;
+
+void main() {
+    // A gaussian blur over the pixels up to one away, in half pixel steps.
+    vec2 offset = 0.5 / source_size;
+    vec3 glow = vec3(0.0);
+    float total = 0.0;
+    for (int x = -2; x <= 2; x++) {
+        for (int y = -2; y <= 2; y++) {
+            float weight = exp(-float(x * x + y * y) / 2.0);
+            glow += texture(tex, v_tex_coords + vec2(x, y) * offset).rgb * weight;
+            total += weight;
+        }
+    }
+    color = vec4(texture(tex, v_tex_coords).rgb + glow / total * STRENGTH, 1.0);
+}
@@ -0,0 +1,32 @@
+#version 140
+
+// A curved tube with scanlines, darkening towards the corners.
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+uniform sampler2D tex;
+uniform vec2 source_size;
+
+// How far the glass bulges out.
+const float CURVATURE = 0.08;
+
+void main() {
+    // Bend the coordinates away from the centre, pulling the picture in
+    // towards it.
+    vec2 centred = v_tex_coords * 2.0 - 1.0;
+    centred *= 1.0 + CURVATURE * dot(centred, centred);
+    vec2 coords = (centred + 1.0) / 2.0;
+    if (coords.x < 0.0 || coords.x > 1.0 || coords.y < 0.0 || coords.y > 1.0) {
+        color = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    vec3 pixel = texture(tex, coords).rgb;
+    float row = fract(coords.y * source_size.y);
+    pixel *= 0.7 + 0.3 * sin(row * 3.14159);
+    // 1 in the centre, falling to 0 at the edges.
+    vec2 edge = coords * (1.0 - coords) * 4.0;
+    pixel *= pow(edge.x * edge.y, 0.15);
+    color = vec4(pixel, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 140
+
+// Outlines every pixel, like the gaps between the cells of an LCD.
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+uniform sampler2D tex;
+uniform vec2 source_size;
+uniform vec2 output_size;
+
+void main() {
+    vec2 cell = fract(v_tex_coords * source_size);
+    // One window pixel, as a fraction of a cell.
+    vec2 line = source_size / output_size;
+    vec3 pixel = texture(tex, v_tex_coords).rgb;
+    if (cell.x < line.x || cell.y < line.y) {
+        pixel *= 0.5;
+    }
+    color = vec4(pixel, 1.0);
+}
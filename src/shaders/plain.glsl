@@ -0,0 +1,20 @@
+#version 140
+
+// Shows the screen as it is.
+//
+// Post-process shaders are GLSL fragment shaders given:
+//   v_tex_coords  where on the screen to shade, from (0, 0) at the top left
+//                 to (1, 1) at the bottom right
+//   tex           the screen, one texel per CHIP-8 pixel
+//   source_size   the screen's size in CHIP-8 pixels
+//   output_size   the size it is drawn at in the window, in window pixels
+// and write the colour to `color`.
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+uniform sampler2D tex;
+
+void main() {
+    color = texture(tex, v_tex_coords);
+}
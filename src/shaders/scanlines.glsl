@@ -0,0 +1,17 @@
+#version 140
+
+// Darkens the bottom and top of every row of pixels, like the gaps between
+// the lines a CRT's beam traces.
+
+in vec2 v_tex_coords;
+out vec4 color;
+
+uniform sampler2D tex;
+uniform vec2 source_size;
+
+void main() {
+    // Where in its row of pixels this is, from 0 at the top to 1 at the bottom.
+    float row = fract(v_tex_coords.y * source_size.y);
+    float brightness = 0.6 + 0.4 * sin(row * 3.14159);
+    color = vec4(texture(tex, v_tex_coords).rgb * brightness, 1.0);
+}
@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use quirks::Profile;
+
+/// The save state format version written by this build. Older versions are
+/// still loaded, newer ones are refused.
+pub const STATE_VERSION: u16 = 6;
+
+/// The first bytes of every save state.
+const MAGIC: &[u8; 4] = b"C8ST";
+
+/// Why a save state could not be loaded.
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    /// The data does not start with the save state magic number.
+    NotAState,
+    /// The state was written by a newer build using this format version.
+    UnsupportedVersion(u16),
+    /// The state was saved while running a different ROM.
+    RomMismatch,
+    /// The state was saved with a different profile, named here.
+    ProfileMismatch(String),
+    /// The state was saved with a different random number generator, named
+    /// here.
+    RandomMismatch(String),
+    /// The state is truncated or holds impossible values.
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref err) => write!(f, "{}", err),
+            StateError::NotAState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "Save state version {} is newer than this build supports", version),
+            StateError::RomMismatch => write!(f, "Save state was made with a different ROM"),
+            StateError::ProfileMismatch(ref profile) =>
+                write!(f, "Save state was made with the {} profile", profile),
+            StateError::RandomMismatch(ref random) =>
+                write!(f, "Save state was made with the {} random number generator", random),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn description(&self) -> &str {
+        match *self {
+            StateError::Io(_) => "i/o error reading save state",
+            StateError::NotAState => "not a save state",
+            StateError::UnsupportedVersion(_) => "unsupported save state version",
+            StateError::RomMismatch => "save state made with a different ROM",
+            StateError::ProfileMismatch(_) => "save state made with a different profile",
+            StateError::RandomMismatch(_) => "save state made with a different random number generator",
+            StateError::Corrupt => "corrupt save state",
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(err: io::Error) -> StateError {
+        StateError::Io(err)
+    }
+}
+
+/// Hashes a ROM with 64-bit FNV-1a, to tell which ROM a state belongs to.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Builds a save state in memory. Numbers are little-endian.
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Starts a state with the header identifying the ROM and profile.
+    pub(crate) fn new(rom_hash: u64, profile: Profile) -> StateWriter {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u16(STATE_VERSION);
+        writer.u64(rom_hash);
+        writer.u8(profile.name().len() as u8);
+        writer.bytes(profile.name().as_bytes());
+        writer
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.bytes(&[value as u8, (value >> 8) as u8]);
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.u16(value as u16);
+        self.u16((value >> 16) as u16);
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.u32(value as u32);
+        self.u32((value >> 32) as u32);
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads back what a `StateWriter` wrote.
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Checks the header against the running ROM and profile, returning a
+    /// reader positioned after it along with the state's version.
+    pub(crate) fn new(bytes: &'a [u8], rom_hash: u64, profile: Profile) -> Result<(StateReader<'a>, u16), StateError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(StateError::NotAState);
+        }
+        let mut reader = StateReader { bytes, pos: MAGIC.len() };
+        let version = reader.u16()?;
+        if version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if reader.u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        let len = reader.u8()? as usize;
+        let name = String::from_utf8_lossy(reader.bytes(len)?).into_owned();
+        if name != profile.name() {
+            return Err(StateError::ProfileMismatch(name));
+        }
+        Ok((reader, version))
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(bytes[0] as u16 | ((bytes[1] as u16) << 8))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(self.u16()? as u32 | ((self.u16()? as u32) << 16))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(self.u32()? as u64 | ((self.u32()? as u64) << 32))
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(StateError::Corrupt);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Fails unless everything has been read.
+    pub(crate) fn finish(self) -> Result<(), StateError> {
+        if self.pos == self.bytes.len() { Ok(()) } else { Err(StateError::Corrupt) }
+    }
+}
@@ -1,7 +1,40 @@
+use audio::AudioOutput;
+use error::CpuError;
+use keypad::Keypad;
+
+/// The host a `Cpu` runs on.
+///
+/// Implementors present the screen to the user, push presses and releases of
+/// the sixteen hex keys into the keypad and optionally play sound.
 pub trait Chip8System {
-    fn render(&mut self, &[u8; 2048]);
+    /// Called at the end of each frame in which the screen changed, with the
+    /// screen, one byte per pixel row by row, and its dimensions. The screen
+    /// is 64x32, or 128x64 while a SUPER-CHIP program has high resolution
+    /// mode enabled. Each byte is a bitmask of the planes the pixel is lit
+    /// on, which is only ever 0 or 1 outside XO-CHIP.
+    fn render(&mut self, screen: &[u8], width: usize, height: usize);
+
+    /// Called at the end of every frame, after `render` if the screen
+    /// changed, and by `Cpu::refresh`. Hosts with a window handle its events
+    /// here.
+    fn end_frame(&mut self) {}
 
-    fn get_input(&mut self) -> Vec<u8>;
+    /// Called once per frame, after `end_frame`, to apply the key events since
+    /// the last call. Hosts without input can leave this empty.
+    fn update_keypad(&mut self, _keypad: &mut Keypad) {}
 
+    /// Returns true once the host wants the interpreter to stop.
     fn is_closed(&mut self) -> bool;
+
+    /// Where to send the tone played while the sound timer is running. Hosts
+    /// without sound can leave this as `None`.
+    fn audio_output(&mut self) -> Option<&mut dyn AudioOutput> {
+        None
+    }
+
+    /// Called with each invalid instruction skipped over outside of strict
+    /// mode. By default these are logged to stderr.
+    fn report_error(&mut self, error: &CpuError) {
+        eprintln!("{}", error);
+    }
 }